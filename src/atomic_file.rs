@@ -0,0 +1,13 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Writes the file through a temporary file next to it that replaces it in one rename,
+/// so a crash while writing never leaves a half written file behind.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)
+}
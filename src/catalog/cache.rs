@@ -0,0 +1,371 @@
+use crate::catalog::{Album, Artist, MusicCatalog, Playlist, Show, User};
+use crate::config::CacheConfig;
+use crate::track::Track;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::atomic_file::write_atomically;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+const DEFAULT_PATH: &str = ".spotify_metadata_cache.json";
+const DEFAULT_TRACK_TTL: i64 = 7 * 24 * 60 * 60;
+const DEFAULT_ALBUM_TTL: i64 = 7 * 24 * 60 * 60;
+const DEFAULT_PLAYLIST_TTL: i64 = 5 * 60;
+const DEFAULT_USER_TTL: i64 = 24 * 60 * 60;
+
+/// How long changes are collected before the cache is written, browsing a directory
+/// easily caches hundreds of tracks one after another
+const WRITE_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+#[derive(Clone, Deserialize, Serialize)]
+struct CacheEntry<T> {
+    value: T,
+    fetched_at: DateTime<Utc>,
+}
+
+impl<T: Clone> CacheEntry<T> {
+    fn new(value: T) -> Self {
+        Self {
+            value,
+            fetched_at: Utc::now(),
+        }
+    }
+
+    fn fresh(&self, ttl: Duration) -> Option<T> {
+        if self.is_fresh(ttl) {
+            Some(self.value.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> CacheEntry<T> {
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        Utc::now() - self.fetched_at < ttl
+    }
+}
+
+/// The tracks of a playlist, valid for as long as the playlist has the same snapshot id
+#[derive(Clone, Deserialize, Serialize)]
+struct PlaylistTracks {
+    snapshot_id: String,
+    tracks: Vec<Track>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct CacheData {
+    tracks: HashMap<String, CacheEntry<Track>>,
+    albums: HashMap<String, CacheEntry<Album>>,
+    album_tracks: HashMap<String, CacheEntry<Vec<Track>>>,
+    playlists: Option<CacheEntry<Vec<Playlist>>>,
+    playlist_tracks: HashMap<String, PlaylistTracks>,
+    user: Option<CacheEntry<User>>,
+}
+
+impl CacheData {
+    /// Drops the entries too old to be used. An expired entry is only replaced when the same
+    /// id is fetched again, so the cache would otherwise keep everything ever browsed.
+    fn prune(&mut self, track_ttl: Duration, album_ttl: Duration) {
+        self.tracks.retain(|_, entry| entry.is_fresh(track_ttl));
+        self.albums.retain(|_, entry| entry.is_fresh(album_ttl));
+        self.album_tracks.retain(|_, entry| entry.is_fresh(album_ttl));
+    }
+}
+
+/// Keeps the metadata we looked up before in front of another catalog, so browsing the
+/// same things again doesn't go back to the Web API. The cache is written to disk shortly
+/// after it changed and read back on startup.
+pub struct MetadataCache {
+    catalog: Box<dyn MusicCatalog>,
+    path: PathBuf,
+    track_ttl: Duration,
+    album_ttl: Duration,
+    playlist_ttl: Duration,
+    user_ttl: Duration,
+    data: Arc<RwLock<CacheData>>,
+    /// Set while a write of the changes is scheduled
+    write_scheduled: Arc<AtomicBool>,
+}
+
+impl MetadataCache {
+    pub fn new(catalog: Box<dyn MusicCatalog>, config: Option<&CacheConfig>) -> Self {
+        let ttl = |ttl: Option<i64>, default: i64| Duration::seconds(ttl.unwrap_or(default));
+        let path = PathBuf::from(config.and_then(|config| config.path.clone()).unwrap_or_else(|| DEFAULT_PATH.to_owned()));
+
+        let track_ttl = ttl(config.and_then(|config| config.track_ttl), DEFAULT_TRACK_TTL);
+        let album_ttl = ttl(config.and_then(|config| config.album_ttl), DEFAULT_ALBUM_TTL);
+
+        // A missing or outdated cache file just means starting with an empty cache
+        let mut data: CacheData = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        data.prune(track_ttl, album_ttl);
+
+        Self {
+            catalog,
+            path,
+            track_ttl,
+            album_ttl,
+            playlist_ttl: ttl(config.and_then(|config| config.playlist_ttl), DEFAULT_PLAYLIST_TTL),
+            user_ttl: ttl(config.and_then(|config| config.user_ttl), DEFAULT_USER_TTL),
+            data: Arc::new(RwLock::new(data)),
+            write_scheduled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Forgets the playlist listing after we changed a playlist, so the new snapshot ids are fetched
+    fn invalidate_playlists(&self) {
+        self.update(|data| data.playlists = None);
+    }
+
+    fn insert_tracks(data: &mut CacheData, tracks: &[Track]) {
+        for track in tracks {
+            if let Some(id) = &track.id {
+                data.tracks.insert(id.clone(), CacheEntry::new(track.clone()));
+            }
+        }
+    }
+
+    fn update<F: FnOnce(&mut CacheData)>(&self, f: F) {
+        f(&mut self.data.write().unwrap());
+
+        if !self.write_scheduled.swap(true, Ordering::SeqCst) {
+            let write = Self::write(self.path.clone(), Arc::clone(&self.data), Arc::clone(&self.write_scheduled), self.track_ttl, self.album_ttl);
+            tokio::spawn(write);
+        }
+    }
+
+    /// Writes the cache once the changes following the first one are in, away from the runtime
+    async fn write(path: PathBuf, data: Arc<RwLock<CacheData>>, write_scheduled: Arc<AtomicBool>, track_ttl: Duration, album_ttl: Duration) {
+        tokio::time::delay_for(WRITE_DELAY).await;
+        // Changes from now on need another write
+        write_scheduled.store(false, Ordering::SeqCst);
+
+        let file = path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            data.write().unwrap().prune(track_ttl, album_ttl);
+            let contents = serde_json::to_string(&*data.read().unwrap()).map_err(|e| e.to_string())?;
+            write_atomically(&file, &contents).map_err(|e| e.to_string())
+        }).await.unwrap_or_else(|e| Err(e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to write metadata cache to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[async_trait]
+impl MusicCatalog for MetadataCache {
+    async fn track(&self, id: &str) -> Result<Track> {
+        if let Some(track) = self.data.read().unwrap().tracks.get(id).and_then(|entry| entry.fresh(self.track_ttl)) {
+            return Ok(track);
+        }
+
+        let track = self.catalog.track(id).await?;
+        self.update(|data| {
+            data.tracks.insert(id.to_owned(), CacheEntry::new(track.clone()));
+        });
+
+        Ok(track)
+    }
+
+    async fn search_tracks(&self, query: &str) -> Result<Vec<Track>> {
+        self.catalog.search_tracks(query).await
+    }
+
+    async fn current_user(&self) -> Result<User> {
+        if let Some(user) = self.data.read().unwrap().user.as_ref().and_then(|entry| entry.fresh(self.user_ttl)) {
+            return Ok(user);
+        }
+
+        let user = self.catalog.current_user().await?;
+        self.update(|data| data.user = Some(CacheEntry::new(user.clone())));
+
+        Ok(user)
+    }
+
+    async fn user_playlists(&self) -> Result<Vec<Playlist>> {
+        if let Some(playlists) = self.data.read().unwrap().playlists.as_ref().and_then(|entry| entry.fresh(self.playlist_ttl)) {
+            return Ok(playlists);
+        }
+
+        let playlists = self.catalog.user_playlists().await?;
+        self.update(|data| {
+            // Drop the tracks of playlists that are gone
+            data.playlist_tracks.retain(|id, _| playlists.iter().any(|playlist| playlist.id == *id));
+            data.playlists = Some(CacheEntry::new(playlists.clone()));
+        });
+
+        Ok(playlists)
+    }
+
+    /// Refetched whenever the snapshot id of the playlist changed
+    async fn playlist_tracks(&self, playlist: &Playlist) -> Result<Vec<Track>> {
+        if let Some(cached) = self.data.read().unwrap().playlist_tracks.get(&playlist.id) {
+            if cached.snapshot_id == playlist.snapshot_id {
+                return Ok(cached.tracks.clone());
+            }
+        }
+
+        let tracks = self.catalog.playlist_tracks(playlist).await?;
+        self.update(|data| {
+            data.playlist_tracks.insert(playlist.id.clone(), PlaylistTracks {
+                snapshot_id: playlist.snapshot_id.clone(),
+                tracks: tracks.clone(),
+            });
+            Self::insert_tracks(data, &tracks);
+        });
+
+        Ok(tracks)
+    }
+
+    async fn create_playlist(&self, name: &str) -> Result<Playlist> {
+        let playlist = self.catalog.create_playlist(name).await?;
+        self.invalidate_playlists();
+
+        Ok(playlist)
+    }
+
+    async fn add_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()> {
+        self.catalog.add_playlist_tracks(playlist, track_ids).await?;
+        self.invalidate_playlists();
+
+        Ok(())
+    }
+
+    async fn remove_playlist_track(&self, playlist: &Playlist, position: usize) -> Result<()> {
+        self.catalog.remove_playlist_track(playlist, position).await?;
+        self.invalidate_playlists();
+
+        Ok(())
+    }
+
+    async fn replace_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()> {
+        self.catalog.replace_playlist_tracks(playlist, track_ids).await?;
+        self.invalidate_playlists();
+
+        Ok(())
+    }
+
+    async fn move_playlist_track(&self, playlist: &Playlist, from: usize, to: usize) -> Result<()> {
+        self.catalog.move_playlist_track(playlist, from, to).await?;
+        self.invalidate_playlists();
+
+        Ok(())
+    }
+
+    async fn rename_playlist(&self, playlist: &Playlist, name: &str) -> Result<()> {
+        self.catalog.rename_playlist(playlist, name).await?;
+        self.invalidate_playlists();
+
+        Ok(())
+    }
+
+    async fn remove_playlist(&self, playlist: &Playlist) -> Result<()> {
+        self.catalog.remove_playlist(playlist).await?;
+        self.invalidate_playlists();
+
+        Ok(())
+    }
+
+    async fn saved_tracks(&self) -> Result<Vec<Track>> {
+        self.catalog.saved_tracks().await
+    }
+
+    async fn saved_albums(&self) -> Result<Vec<Album>> {
+        self.catalog.saved_albums().await
+    }
+
+    async fn album(&self, id: &str) -> Result<Album> {
+        if let Some(album) = self.data.read().unwrap().albums.get(id).and_then(|entry| entry.fresh(self.album_ttl)) {
+            return Ok(album);
+        }
+
+        let album = self.catalog.album(id).await?;
+        self.update(|data| {
+            data.albums.insert(id.to_owned(), CacheEntry::new(album.clone()));
+        });
+
+        Ok(album)
+    }
+
+    async fn album_tracks(&self, album: &Album) -> Result<Vec<Track>> {
+        if let Some(tracks) = self.data.read().unwrap().album_tracks.get(&album.id).and_then(|entry| entry.fresh(self.album_ttl)) {
+            return Ok(tracks);
+        }
+
+        let tracks = self.catalog.album_tracks(album).await?;
+        self.update(|data| {
+            data.album_tracks.insert(album.id.clone(), CacheEntry::new(tracks.clone()));
+            Self::insert_tracks(data, &tracks);
+        });
+
+        Ok(tracks)
+    }
+
+    async fn followed_artists(&self) -> Result<Vec<Artist>> {
+        self.catalog.followed_artists().await
+    }
+
+    async fn artist_albums(&self, artist: &Artist) -> Result<Vec<Album>> {
+        self.catalog.artist_albums(artist).await
+    }
+
+    async fn saved_shows(&self) -> Result<Vec<Show>> {
+        self.catalog.saved_shows().await
+    }
+
+    async fn show_episodes(&self, show: &Show) -> Result<Vec<Track>> {
+        self.catalog.show_episodes(show).await
+    }
+
+    async fn episode(&self, id: &str) -> Result<Track> {
+        self.catalog.episode(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::fixture_track;
+
+    fn album(id: &str) -> Album {
+        Album {
+            id: id.to_owned(),
+            name: "Abbey Road".to_owned(),
+            artists: vec!["The Beatles".to_owned()],
+            release_date: "1969-09-26".to_owned(),
+        }
+    }
+
+    fn fetched_ago<T>(value: T, age: Duration) -> CacheEntry<T> {
+        CacheEntry {
+            value,
+            fetched_at: Utc::now() - age,
+        }
+    }
+
+    #[test]
+    fn prune_drops_expired_entries_only() {
+        let album = album("album1");
+        let track = fixture_track("track1", "Something", &album, 2, 182_000);
+        let mut data = CacheData::default();
+        data.tracks.insert("fresh".to_owned(), fetched_ago(track.clone(), Duration::hours(1)));
+        data.tracks.insert("expired".to_owned(), fetched_ago(track.clone(), Duration::days(2)));
+        data.albums.insert("fresh".to_owned(), fetched_ago(album.clone(), Duration::days(2)));
+        data.albums.insert("expired".to_owned(), fetched_ago(album.clone(), Duration::days(4)));
+        data.album_tracks.insert("expired".to_owned(), fetched_ago(vec![track], Duration::days(4)));
+
+        data.prune(Duration::days(1), Duration::days(3));
+
+        assert_eq!(data.tracks.keys().collect::<Vec<_>>(), vec!["fresh"]);
+        assert_eq!(data.albums.keys().collect::<Vec<_>>(), vec!["fresh"]);
+        assert!(data.album_tracks.is_empty());
+    }
+}
@@ -0,0 +1,394 @@
+use crate::catalog::{Album, Artist, MusicCatalog, NotFound, Playlist, Show, User};
+use crate::track::Track;
+use anyhow::{anyhow, Error, Result};
+use async_trait::async_trait;
+use std::sync::RwLock;
+
+struct FixturePlaylist {
+    playlist: Playlist,
+    track_ids: Vec<String>,
+}
+
+struct FixtureAlbum {
+    album: Album,
+    track_ids: Vec<String>,
+}
+
+struct FixtureArtist {
+    artist: Artist,
+    album_ids: Vec<String>,
+}
+
+#[derive(Default)]
+struct FixtureData {
+    tracks: Vec<Track>,
+    playlists: Vec<FixturePlaylist>,
+    saved_tracks: Vec<String>,
+    albums: Vec<FixtureAlbum>,
+    saved_albums: Vec<String>,
+    artists: Vec<FixtureArtist>,
+    followed_artists: Vec<String>,
+    shows: Vec<(Show, Vec<Track>)>,
+    /// Counts up for every created playlist and every playlist change
+    revision: usize,
+}
+
+impl FixtureData {
+    fn track(&self, id: &str) -> Result<Track> {
+        self.tracks
+            .iter()
+            .find(|track| track.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| Error::new(NotFound(format!("No such track: {}", id))))
+    }
+
+    fn tracks(&self, ids: &[String]) -> Vec<Track> {
+        ids.iter().filter_map(|id| self.track(id).ok()).collect()
+    }
+
+    fn playlist_mut(&mut self, playlist: &Playlist) -> Result<&mut FixturePlaylist> {
+        self.revision += 1;
+        let revision = self.revision;
+        let fixture = self.playlists
+            .iter_mut()
+            .find(|fixture| fixture.playlist.id == playlist.id)
+            .ok_or_else(|| anyhow!("No such playlist: {}", playlist.name))?;
+        fixture.playlist.snapshot_id = revision.to_string();
+
+        Ok(fixture)
+    }
+}
+
+/// An in-memory catalog, so the MPD layer can run without network access or a Spotify
+/// account. It starts out empty and is filled with the `add_*` methods, playlist changes
+/// made through the catalog are kept in memory.
+pub struct FixtureCatalog {
+    user: User,
+    data: RwLock<FixtureData>,
+}
+
+impl FixtureCatalog {
+    pub fn new(user_id: &str) -> Self {
+        Self {
+            user: User {
+                id: user_id.to_owned(),
+                display_name: Some(user_id.to_owned()),
+            },
+            data: RwLock::new(FixtureData::default()),
+        }
+    }
+
+    /// A small library of made up music to show clients something without a Spotify account
+    pub fn demo() -> Self {
+        let catalog = Self::new("demo");
+        let album = Album {
+            id: "album1".to_owned(),
+            name: "Offline".to_owned(),
+            artists: vec!["The Fixtures".to_owned()],
+            release_date: "2020-01-01".to_owned(),
+        };
+        let tracks: Vec<Track> = ["Intro", "No Network", "Outro"]
+            .iter()
+            .enumerate()
+            .map(|(index, title)| fixture_track(&format!("track{}", index + 1), title, &album, index as u32 + 1, 180_000))
+            .collect();
+        let track_ids: Vec<String> = tracks.iter().filter_map(|track| track.id.clone()).collect();
+
+        catalog.add_album(album, tracks);
+        catalog.save_album("album1");
+        catalog.add_artist(Artist { id: "artist1".to_owned(), name: "The Fixtures".to_owned() }, vec!["album1".to_owned()]);
+        catalog.follow_artist("artist1");
+        catalog.save_track("track2");
+        catalog.add_playlist("Demo", track_ids);
+
+        catalog
+    }
+
+    pub fn add_track(&self, track: Track) {
+        self.data.write().unwrap().tracks.push(track);
+    }
+
+    /// Adds the album and its tracks to the catalog
+    pub fn add_album(&self, album: Album, tracks: Vec<Track>) {
+        let mut data = self.data.write().unwrap();
+        let track_ids = tracks.iter().filter_map(|track| track.id.clone()).collect();
+        data.tracks.extend(tracks);
+        data.albums.push(FixtureAlbum { album, track_ids });
+    }
+
+    pub fn add_artist(&self, artist: Artist, album_ids: Vec<String>) {
+        self.data.write().unwrap().artists.push(FixtureArtist { artist, album_ids });
+    }
+
+    /// Adds a playlist of tracks already in the catalog to the user's library
+    pub fn add_playlist(&self, name: &str, track_ids: Vec<String>) -> Playlist {
+        let mut data = self.data.write().unwrap();
+        data.revision += 1;
+        let playlist = Playlist {
+            id: format!("playlist{}", data.revision),
+            name: name.to_owned(),
+            owner_id: self.user.id.clone(),
+            snapshot_id: data.revision.to_string(),
+        };
+        data.playlists.push(FixturePlaylist { playlist: playlist.clone(), track_ids });
+
+        playlist
+    }
+
+    pub fn add_show(&self, show: Show, episodes: Vec<Track>) {
+        self.data.write().unwrap().shows.push((show, episodes));
+    }
+
+    pub fn save_track(&self, id: &str) {
+        self.data.write().unwrap().saved_tracks.push(id.to_owned());
+    }
+
+    pub fn save_album(&self, id: &str) {
+        self.data.write().unwrap().saved_albums.push(id.to_owned());
+    }
+
+    pub fn follow_artist(&self, id: &str) {
+        self.data.write().unwrap().followed_artists.push(id.to_owned());
+    }
+}
+
+#[async_trait]
+impl MusicCatalog for FixtureCatalog {
+    async fn track(&self, id: &str) -> Result<Track> {
+        self.data.read().unwrap().track(id)
+    }
+
+    /// Matches every term of the query against the title, artists and album,
+    /// field prefixes like `artist:` only narrow down the terms Spotify looks at.
+    async fn search_tracks(&self, query: &str) -> Result<Vec<Track>> {
+        let terms = search_terms(query);
+
+        Ok(self.data.read().unwrap().tracks
+            .iter()
+            .filter(|track| terms.iter().all(|term| {
+                track.title.to_lowercase().contains(term)
+                    || track.album.to_lowercase().contains(term)
+                    || track.date.contains(term.as_str())
+                    || track.artists.iter().any(|artist| artist.to_lowercase().contains(term))
+            }))
+            .cloned()
+            .collect())
+    }
+
+    async fn current_user(&self) -> Result<User> {
+        Ok(self.user.clone())
+    }
+
+    async fn user_playlists(&self) -> Result<Vec<Playlist>> {
+        Ok(self.data.read().unwrap().playlists.iter().map(|fixture| fixture.playlist.clone()).collect())
+    }
+
+    async fn playlist_tracks(&self, playlist: &Playlist) -> Result<Vec<Track>> {
+        let data = self.data.read().unwrap();
+        let fixture = data.playlists
+            .iter()
+            .find(|fixture| fixture.playlist.id == playlist.id)
+            .ok_or_else(|| anyhow!("No such playlist: {}", playlist.name))?;
+
+        Ok(data.tracks(&fixture.track_ids))
+    }
+
+    async fn create_playlist(&self, name: &str) -> Result<Playlist> {
+        Ok(self.add_playlist(name, vec![]))
+    }
+
+    async fn add_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        data.playlist_mut(playlist)?.track_ids.extend_from_slice(track_ids);
+
+        Ok(())
+    }
+
+    async fn remove_playlist_track(&self, playlist: &Playlist, position: usize) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        let track_ids = &mut data.playlist_mut(playlist)?.track_ids;
+        if position >= track_ids.len() {
+            return Err(anyhow!("Bad song index"));
+        }
+        track_ids.remove(position);
+
+        Ok(())
+    }
+
+    async fn replace_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        data.playlist_mut(playlist)?.track_ids = track_ids.to_vec();
+
+        Ok(())
+    }
+
+    async fn move_playlist_track(&self, playlist: &Playlist, from: usize, to: usize) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        let track_ids = &mut data.playlist_mut(playlist)?.track_ids;
+        if from >= track_ids.len() || to >= track_ids.len() {
+            return Err(anyhow!("Bad song index"));
+        }
+        let track_id = track_ids.remove(from);
+        track_ids.insert(to, track_id);
+
+        Ok(())
+    }
+
+    async fn rename_playlist(&self, playlist: &Playlist, name: &str) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        data.playlist_mut(playlist)?.playlist.name = name.to_owned();
+
+        Ok(())
+    }
+
+    async fn remove_playlist(&self, playlist: &Playlist) -> Result<()> {
+        self.data.write().unwrap().playlists.retain(|fixture| fixture.playlist.id != playlist.id);
+
+        Ok(())
+    }
+
+    async fn saved_tracks(&self) -> Result<Vec<Track>> {
+        let data = self.data.read().unwrap();
+
+        Ok(data.tracks(&data.saved_tracks))
+    }
+
+    async fn saved_albums(&self) -> Result<Vec<Album>> {
+        let data = self.data.read().unwrap();
+
+        Ok(data.albums
+            .iter()
+            .filter(|fixture| data.saved_albums.contains(&fixture.album.id))
+            .map(|fixture| fixture.album.clone())
+            .collect())
+    }
+
+    async fn album(&self, id: &str) -> Result<Album> {
+        self.data.read().unwrap().albums
+            .iter()
+            .find(|fixture| fixture.album.id == id)
+            .map(|fixture| fixture.album.clone())
+            .ok_or_else(|| anyhow!("No such album: {}", id))
+    }
+
+    async fn album_tracks(&self, album: &Album) -> Result<Vec<Track>> {
+        let data = self.data.read().unwrap();
+        let fixture = data.albums
+            .iter()
+            .find(|fixture| fixture.album.id == album.id)
+            .ok_or_else(|| anyhow!("No such album: {}", album.id))?;
+
+        Ok(data.tracks(&fixture.track_ids))
+    }
+
+    async fn followed_artists(&self) -> Result<Vec<Artist>> {
+        let data = self.data.read().unwrap();
+
+        Ok(data.artists
+            .iter()
+            .filter(|fixture| data.followed_artists.contains(&fixture.artist.id))
+            .map(|fixture| fixture.artist.clone())
+            .collect())
+    }
+
+    async fn artist_albums(&self, artist: &Artist) -> Result<Vec<Album>> {
+        let data = self.data.read().unwrap();
+        let fixture = data.artists
+            .iter()
+            .find(|fixture| fixture.artist.id == artist.id)
+            .ok_or_else(|| anyhow!("No such artist: {}", artist.id))?;
+
+        Ok(data.albums
+            .iter()
+            .filter(|album| fixture.album_ids.contains(&album.album.id))
+            .map(|album| album.album.clone())
+            .collect())
+    }
+
+    async fn saved_shows(&self) -> Result<Vec<Show>> {
+        Ok(self.data.read().unwrap().shows.iter().map(|(show, _)| show.clone()).collect())
+    }
+
+    async fn show_episodes(&self, show: &Show) -> Result<Vec<Track>> {
+        self.data.read().unwrap().shows
+            .iter()
+            .find(|(fixture, _)| fixture.id == show.id)
+            .map(|(_, episodes)| episodes.clone())
+            .ok_or_else(|| anyhow!("No such show: {}", show.id))
+    }
+
+    async fn episode(&self, id: &str) -> Result<Track> {
+        self.data.read().unwrap().shows
+            .iter()
+            .flat_map(|(_, episodes)| episodes)
+            .find(|episode| episode.id.as_deref() == Some(id))
+            .cloned()
+            .ok_or_else(|| Error::new(NotFound(format!("No such episode: {}", id))))
+    }
+}
+
+/// Builds a track of the album with the album's artists
+pub fn fixture_track(id: &str, title: &str, album: &Album, track_number: u32, duration_ms: u32) -> Track {
+    Track {
+        id: Some(id.to_owned()),
+        title: title.to_owned(),
+        track_number,
+        disc_number: 1,
+        duration: duration_ms,
+        artists: album.artists.clone(),
+        album: album.name.clone(),
+        album_id: Some(album.id.clone()),
+        album_artists: album.artists.clone(),
+        url: format!("spotify:track:{}", id),
+        added_at: None,
+        date: album.release_date.clone(),
+    }
+}
+
+/// Builds an episode of the show the way Spotify lists them
+pub fn fixture_episode(id: &str, title: &str, show: &Show, release_date: &str, duration_ms: u32) -> Track {
+    Track {
+        id: Some(id.to_owned()),
+        title: title.to_owned(),
+        track_number: 0,
+        disc_number: 0,
+        duration: duration_ms,
+        artists: vec![show.publisher.clone()],
+        album: show.name.clone(),
+        album_id: None,
+        album_artists: vec![show.publisher.clone()],
+        url: format!("spotify:episode:{}", id),
+        added_at: None,
+        date: release_date.to_owned(),
+    }
+}
+
+/// Splits a Spotify search query into lowercase terms, dropping field prefixes and quotes
+fn search_terms(query: &str) -> Vec<String> {
+    let mut terms = vec![];
+    let mut chars = query.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut term = String::new();
+        let mut quoted = false;
+        while let Some(&c) = chars.peek() {
+            match c {
+                '"' => quoted = !quoted,
+                ':' if !quoted => term.clear(),
+                c if c.is_whitespace() && !quoted => break,
+                c => term.push(c),
+            }
+            chars.next();
+        }
+        if !term.is_empty() {
+            terms.push(term.to_lowercase());
+        }
+    }
+
+    terms
+}
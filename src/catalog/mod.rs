@@ -0,0 +1,116 @@
+use crate::track::Track;
+use anyhow::Result;
+use async_trait::async_trait;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+pub use crate::catalog::cache::MetadataCache;
+pub use crate::catalog::fixture::{fixture_episode, fixture_track, FixtureCatalog};
+pub use crate::catalog::spotify::SpotifyCatalog;
+
+mod cache;
+mod fixture;
+mod spotify;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct User {
+    pub id: String,
+    pub display_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub owner_id: String,
+    /// Changes whenever the tracks of the playlist change
+    pub snapshot_id: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub release_date: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Artist {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Show {
+    pub id: String,
+    pub name: String,
+    pub publisher: String,
+}
+
+/// The error for an id the catalog knows nothing about, as opposed to a lookup that failed
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Everything the MPD layer needs to know about the music it serves. Collections are
+/// returned in full, implementations take care of paging through them.
+#[async_trait]
+pub trait MusicCatalog: Send + Sync {
+    /// Fails with [`NotFound`] when there is no track with the id
+    async fn track(&self, id: &str) -> Result<Track>;
+
+    /// The matches for a Spotify style search query like `artist:"x" track:"y"`, best first
+    async fn search_tracks(&self, query: &str) -> Result<Vec<Track>>;
+
+    async fn current_user(&self) -> Result<User>;
+
+    async fn user_playlists(&self) -> Result<Vec<Playlist>>;
+
+    /// The playable tracks of the playlist
+    async fn playlist_tracks(&self, playlist: &Playlist) -> Result<Vec<Track>>;
+
+    async fn create_playlist(&self, name: &str) -> Result<Playlist>;
+
+    async fn add_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()>;
+
+    /// Removes the track at `position` of the playable tracks, everything else stays in place
+    async fn remove_playlist_track(&self, playlist: &Playlist, position: usize) -> Result<()>;
+
+    async fn replace_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()>;
+
+    /// Moves the track at `from` so that it ends up at `to`
+    async fn move_playlist_track(&self, playlist: &Playlist, from: usize, to: usize) -> Result<()>;
+
+    async fn rename_playlist(&self, playlist: &Playlist, name: &str) -> Result<()>;
+
+    /// Removes the playlist from the user's library
+    async fn remove_playlist(&self, playlist: &Playlist) -> Result<()>;
+
+    async fn saved_tracks(&self) -> Result<Vec<Track>>;
+
+    async fn saved_albums(&self) -> Result<Vec<Album>>;
+
+    async fn album(&self, id: &str) -> Result<Album>;
+
+    async fn album_tracks(&self, album: &Album) -> Result<Vec<Track>>;
+
+    async fn followed_artists(&self) -> Result<Vec<Artist>>;
+
+    async fn artist_albums(&self, artist: &Artist) -> Result<Vec<Album>>;
+
+    async fn saved_shows(&self) -> Result<Vec<Show>>;
+
+    /// The episodes of the show, as tracks of an album named after it
+    async fn show_episodes(&self, show: &Show) -> Result<Vec<Track>>;
+
+    /// Fails with [`NotFound`] when there is no episode with the id
+    async fn episode(&self, id: &str) -> Result<Track>;
+}
@@ -0,0 +1,452 @@
+use crate::catalog::{Album, Artist, MusicCatalog, NotFound, Playlist, Show, User};
+use crate::spotify::{paginate, paginate_cursor, SharedSpotify, PAGE_SIZE};
+use crate::track::Track;
+use anyhow::{anyhow, Error, Result};
+use async_trait::async_trait;
+use core::fmt;
+use futures::{StreamExt, TryFutureExt, TryStreamExt};
+use rspotify::client::{ApiError, CLIENT};
+use rspotify::model::album::FullAlbum;
+use rspotify::model::playlist::{FullPlaylist, PlaylistTrack, SimplifiedPlaylist};
+use rspotify::model::track::FullTrack;
+use serde_json::{json, Value};
+
+/// The most results Spotify returns for a search across all pages
+const SEARCH_LIMIT: usize = 1000;
+
+/// The catalog as the Spotify Web API sees it
+pub struct SpotifyCatalog {
+    spotify: SharedSpotify,
+}
+
+impl SpotifyCatalog {
+    pub fn new(spotify: SharedSpotify) -> Self {
+        Self { spotify }
+    }
+
+    /// Sends a DELETE with a body rspotify can't build, like the `positions` of the tracks to remove
+    async fn delete(&self, path: &str, body: &Value) -> Result<()> {
+        let spotify = self.spotify.get();
+        let access_token = match spotify.client_credentials_manager {
+            Some(ref credentials) => credentials.get_access_token().await,
+            None => spotify.access_token.clone().unwrap_or_default(),
+        };
+        let response = CLIENT.delete(&format!("{}{}", spotify.prefix, path))
+            .bearer_auth(access_token)
+            .json(body)
+            .send()
+            .await
+            .map_err(api_error)?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(api_error(ApiError::from(&response)))
+        }
+    }
+
+    /// Every item of the playlist, including the local tracks and episodes we can't play
+    async fn playlist_items(&self, playlist: &Playlist) -> Result<Vec<PlaylistTrack>> {
+        let spotify = self.spotify.get();
+
+        paginate(|offset| spotify
+            .user_playlist_tracks(&playlist.owner_id, &playlist.id, None, 100, offset, None))
+            .try_collect()
+            .await
+            .map_err(api_error)
+    }
+}
+
+#[async_trait]
+impl MusicCatalog for SpotifyCatalog {
+    async fn track(&self, id: &str) -> Result<Track> {
+        let full_track = self.spotify.get().track(id).await
+            .map_err(|error| if is_unknown_id(error.downcast_ref()) {
+                Error::new(NotFound(format!("No such track: {}", id)))
+            } else {
+                api_error(error)
+            })?;
+
+        Ok(Track::from(&full_track))
+    }
+
+    async fn search_tracks(&self, query: &str) -> Result<Vec<Track>> {
+        let spotify = self.spotify.get();
+        let tracks: Vec<_> = paginate(|offset| spotify
+            .search_track(query, PAGE_SIZE, offset, None)
+            .map_ok(|search_result| search_result.tracks))
+            // Spotify refuses to page any further
+            .take(SEARCH_LIMIT)
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(tracks.iter().map(Track::from).collect())
+    }
+
+    async fn current_user(&self) -> Result<User> {
+        let user = self.spotify.get().current_user().await
+            .map_err(api_error)?;
+
+        Ok(User {
+            id: user.id,
+            display_name: user.display_name,
+        })
+    }
+
+    async fn user_playlists(&self) -> Result<Vec<Playlist>> {
+        let spotify = self.spotify.get();
+        let playlists: Vec<SimplifiedPlaylist> = paginate(|offset| spotify.current_user_playlists(PAGE_SIZE, offset))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(playlists.iter().map(Playlist::from).collect())
+    }
+
+    async fn playlist_tracks(&self, playlist: &Playlist) -> Result<Vec<Track>> {
+        let playlist_tracks = self.playlist_items(playlist).await?;
+
+        Ok(playlist_tracks
+            .iter()
+            .filter_map(playable_track)
+            .map(Track::from)
+            .collect())
+    }
+
+    async fn create_playlist(&self, name: &str) -> Result<Playlist> {
+        let user = self.current_user().await?;
+        let playlist = self.spotify.get().user_playlist_create(&user.id, name, false, None::<String>).await
+            .map_err(api_error)?;
+
+        Ok(Playlist::from(&playlist))
+    }
+
+    async fn add_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()> {
+        // Spotify takes at most 100 tracks per request
+        for chunk in track_ids.chunks(100) {
+            self.spotify.get().user_playlist_add_tracks(&playlist.owner_id, &playlist.id, chunk, None).await
+                .map_err(api_error)?;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_playlist_track(&self, playlist: &Playlist, position: usize) -> Result<()> {
+        // The playlist we are handed may be cached, the positions count in the current snapshot
+        let mut playlist_id = playlist.id.clone();
+        let snapshot_id = self.spotify.get().user_playlist(&playlist.owner_id, Some(&mut playlist_id), None, None).await
+            .map_err(api_error)?
+            .snapshot_id;
+        let playlist_tracks = self.playlist_items(playlist).await?;
+        let body = remove_track_body(&playlist_tracks, position, &snapshot_id)
+            .ok_or_else(|| anyhow!("Bad song index"))?;
+
+        self.delete(&format!("playlists/{}/tracks", playlist.id), &body).await
+    }
+
+    async fn replace_playlist_tracks(&self, playlist: &Playlist, track_ids: &[String]) -> Result<()> {
+        let mut chunks = track_ids.chunks(100);
+
+        self.spotify.get().user_playlist_replace_tracks(&playlist.owner_id, &playlist.id, chunks.next().unwrap_or(&[])).await
+            .map_err(api_error)?;
+        for chunk in chunks {
+            self.spotify.get().user_playlist_add_tracks(&playlist.owner_id, &playlist.id, chunk, None).await
+                .map_err(api_error)?;
+        }
+
+        Ok(())
+    }
+
+    async fn move_playlist_track(&self, playlist: &Playlist, from: usize, to: usize) -> Result<()> {
+        // The positions count the playable tracks only, Spotify counts every item
+        let item_positions: Vec<_> = self.playlist_items(playlist).await?
+            .iter()
+            .enumerate()
+            .filter(|(_, playlist_track)| playable_track(playlist_track).is_some())
+            .map(|(item_position, _)| item_position)
+            .collect();
+        let item_position = |position: usize| item_positions.get(position).copied().ok_or_else(|| anyhow!("Bad song index"));
+        let (from_item, to_item) = (item_position(from)?, item_position(to)?);
+
+        // Spotify inserts before the given position, counted before the track is taken out
+        let insert_before = if to > from { to_item + 1 } else { to_item };
+        self.spotify.get().user_playlist_reorder_tracks(
+            &playlist.owner_id,
+            &playlist.id,
+            from_item as i32,
+            Some(1u32),
+            insert_before as i32,
+            None,
+        ).await.map_err(api_error)?;
+
+        Ok(())
+    }
+
+    async fn rename_playlist(&self, playlist: &Playlist, name: &str) -> Result<()> {
+        self.spotify.get().user_playlist_change_detail(
+            &playlist.owner_id,
+            &playlist.id,
+            Some(name),
+            None,
+            None,
+            None,
+        ).await.map_err(api_error)?;
+
+        Ok(())
+    }
+
+    // Spotify never deletes playlists, unfollowing removes it from the user's library
+    async fn remove_playlist(&self, playlist: &Playlist) -> Result<()> {
+        self.spotify.get().user_playlist_unfollow(&playlist.owner_id, &playlist.id).await
+            .map_err(api_error)?;
+
+        Ok(())
+    }
+
+    async fn saved_tracks(&self) -> Result<Vec<Track>> {
+        let spotify = self.spotify.get();
+        let saved_tracks: Vec<_> = paginate(|offset| spotify.current_user_saved_tracks(PAGE_SIZE, offset))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(saved_tracks.iter().map(|saved_track| Track::from(&saved_track.track)).collect())
+    }
+
+    async fn saved_albums(&self) -> Result<Vec<Album>> {
+        let spotify = self.spotify.get();
+        let saved_albums: Vec<_> = paginate(|offset| spotify.current_user_saved_albums(PAGE_SIZE, offset))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(saved_albums.iter().map(|saved_album| Album::from(&saved_album.album)).collect())
+    }
+
+    async fn album(&self, id: &str) -> Result<Album> {
+        let album = self.spotify.get().album(id).await
+            .map_err(api_error)?;
+
+        Ok(Album::from(&album))
+    }
+
+    async fn album_tracks(&self, album: &Album) -> Result<Vec<Track>> {
+        let spotify = self.spotify.get();
+        let album_tracks: Vec<_> = paginate(|offset| spotify.album_track(&album.id, PAGE_SIZE, offset))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(album_tracks.iter().map(|track| Track::from_album_track(track, album)).collect())
+    }
+
+    async fn followed_artists(&self) -> Result<Vec<Artist>> {
+        let spotify = &self.spotify.get();
+        let artists: Vec<_> = paginate_cursor(|after| async move {
+            spotify.current_user_followed_artists(PAGE_SIZE, after).await
+                .map(|followed_artists| followed_artists.artists)
+        })
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(artists
+            .into_iter()
+            .map(|artist| Artist {
+                id: artist.id,
+                name: artist.name,
+            })
+            .collect())
+    }
+
+    async fn artist_albums(&self, artist: &Artist) -> Result<Vec<Album>> {
+        let spotify = self.spotify.get();
+        let albums: Vec<_> = paginate(|offset| spotify
+            .artist_albums(&artist.id, None, None, Some(PAGE_SIZE), Some(offset)))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(albums
+            .into_iter()
+            .filter_map(|album| Some(Album {
+                id: album.id?,
+                name: album.name,
+                artists: album.artists.into_iter().map(|artist| artist.name).collect(),
+                release_date: album.release_date.unwrap_or_default(),
+            }))
+            .collect())
+    }
+
+    async fn saved_shows(&self) -> Result<Vec<Show>> {
+        let spotify = self.spotify.get();
+        let shows: Vec<_> = paginate(|offset| spotify.get_saved_show(PAGE_SIZE, offset))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(shows
+            .into_iter()
+            .map(|show| Show {
+                id: show.show.id,
+                name: show.show.name,
+                publisher: show.show.publisher,
+            })
+            .collect())
+    }
+
+    async fn show_episodes(&self, show: &Show) -> Result<Vec<Track>> {
+        let spotify = self.spotify.get();
+        let episodes: Vec<_> = paginate(|offset| spotify
+            .get_shows_episodes(show.id.clone(), PAGE_SIZE, offset, None))
+            .try_collect()
+            .await
+            .map_err(api_error)?;
+
+        Ok(episodes.iter().map(|episode| Track::from_episode(episode, show)).collect())
+    }
+
+    async fn episode(&self, id: &str) -> Result<Track> {
+        let episode = self.spotify.get().get_an_episode(id.to_owned(), None).await
+            .map_err(|error| if is_unknown_id(error.downcast_ref()) {
+                Error::new(NotFound(format!("No such episode: {}", id)))
+            } else {
+                api_error(error)
+            })?;
+
+        Ok(Track::from_full_episode(&episode))
+    }
+}
+
+impl From<&SimplifiedPlaylist> for Playlist {
+    fn from(playlist: &SimplifiedPlaylist) -> Self {
+        Self {
+            id: playlist.id.clone(),
+            name: playlist.name.clone(),
+            owner_id: playlist.owner.id.clone(),
+            snapshot_id: playlist.snapshot_id.clone(),
+        }
+    }
+}
+
+impl From<&FullPlaylist> for Playlist {
+    fn from(playlist: &FullPlaylist) -> Self {
+        Self {
+            id: playlist.id.clone(),
+            name: playlist.name.clone(),
+            owner_id: playlist.owner.id.clone(),
+            snapshot_id: playlist.snapshot_id.clone(),
+        }
+    }
+}
+
+impl From<&FullAlbum> for Album {
+    fn from(album: &FullAlbum) -> Self {
+        Self {
+            id: album.id.clone(),
+            name: album.name.clone(),
+            artists: album.artists.iter().map(|artist| artist.name.clone()).collect(),
+            release_date: album.release_date.clone(),
+        }
+    }
+}
+
+/// The track of a playlist item, unless it is a local track we don't support
+fn playable_track(playlist_track: &PlaylistTrack) -> Option<&FullTrack> {
+    playlist_track.track
+        .as_ref()
+        .filter(|track| !track.is_local && track.id.is_some())
+}
+
+/// The request body removing the track at `position`, which counts the playable tracks only.
+/// Spotify counts every item and removes every occurrence of a track unless it gets their `positions`.
+fn remove_track_body(playlist_tracks: &[PlaylistTrack], position: usize, snapshot_id: &str) -> Option<Value> {
+    let (item_position, track) = playlist_tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(item_position, playlist_track)| Some((item_position, playable_track(playlist_track)?)))
+        .nth(position)?;
+
+    Some(json!({
+        "tracks": [{
+            "uri": track.uri,
+            "positions": [item_position],
+        }],
+        "snapshot_id": snapshot_id,
+    }))
+}
+
+/// Spotify answers ids it doesn't know with 404 and malformed ones with 400
+fn is_unknown_id(error: Option<&ApiError>) -> bool {
+    matches!(error, Some(ApiError::Other(400)) | Some(ApiError::Other(404)))
+}
+
+/// rspotify errors don't implement `std::error::Error`, so they are kept as their message
+fn api_error<E: fmt::Display>(error: E) -> Error {
+    anyhow!("{}", error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist_track(id: &str, is_local: bool) -> PlaylistTrack {
+        serde_json::from_value(json!({
+            "added_at": "2020-01-01T00:00:00Z",
+            "added_by": null,
+            "is_local": is_local,
+            "track": {
+                "album": {
+                    "album_type": "album",
+                    "artists": [],
+                    "external_urls": {},
+                    "href": null,
+                    "id": "album",
+                    "images": [],
+                    "name": "Album",
+                    "type": "album",
+                    "uri": "spotify:album:album",
+                },
+                "artists": [],
+                "disc_number": 1,
+                "duration_ms": 180_000,
+                "explicit": false,
+                "external_ids": {},
+                "external_urls": {},
+                "href": null,
+                "id": if is_local { None } else { Some(id) },
+                "is_local": is_local,
+                "name": id,
+                "popularity": 0,
+                "preview_url": null,
+                "track_number": 1,
+                "type": "track",
+                "uri": format!("spotify:track:{}", id),
+            },
+        })).unwrap()
+    }
+
+    #[test]
+    fn removes_one_of_duplicated_tracks_by_its_item_position() {
+        let playlist_tracks = vec![
+            playlist_track("first", false),
+            playlist_track("local", true),
+            playlist_track("twice", false),
+            playlist_track("twice", false),
+        ];
+
+        // The local track isn't listed, so the second `twice` is song 2 but item 3
+        assert_eq!(remove_track_body(&playlist_tracks, 2, "snapshot"), Some(json!({
+            "tracks": [{"uri": "spotify:track:twice", "positions": [3]}],
+            "snapshot_id": "snapshot",
+        })));
+    }
+
+    #[test]
+    fn removing_past_the_last_track_fails() {
+        let playlist_tracks = vec![playlist_track("first", false), playlist_track("local", true)];
+
+        assert_eq!(remove_track_body(&playlist_tracks, 1, "snapshot"), None);
+    }
+}
@@ -6,6 +6,8 @@ use anyhow::Result;
 pub struct Config {
     pub spotify: Option<SpotifyConfig>,
     pub mpd: Option<MpdConfig>,
+    pub cache: Option<CacheConfig>,
+    pub player: Option<PlayerConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -21,6 +23,31 @@ pub struct SpotifyConfig {
 pub struct MpdConfig {
     pub ip: Option<String>,
     pub port: Option<u16>,
+    pub state_file: Option<String>,
+    pub resume_playback: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheConfig {
+    pub path: Option<String>,
+    pub track_ttl: Option<i64>,
+    pub album_ttl: Option<i64>,
+    pub playlist_ttl: Option<i64>,
+    pub user_ttl: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlayerConfig {
+    pub backend: Option<PlayerBackend>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerBackend {
+    /// Streams from Spotify to the sound card, needs a Spotify account
+    Librespot,
+    /// Plays nothing, tracks end after their duration
+    Simulated,
 }
 
 impl Config {
@@ -32,6 +59,10 @@ impl Config {
         Ok(config)
     }
 
+    pub fn get_player_backend(&self) -> PlayerBackend {
+        self.player.as_ref().and_then(|player| player.backend).unwrap_or(PlayerBackend::Librespot)
+    }
+
     pub fn get_redirect_uri(&self) -> String {
         format!("http://127.0.0.1:{}/callback", self.spotify.as_ref().unwrap().port.unwrap())
     }
@@ -0,0 +1,16 @@
+#[macro_use(lazy_static)]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+pub mod catalog;
+pub mod config;
+pub mod mpd;
+pub mod spotify;
+pub mod state;
+mod atomic_file;
+mod redirect_uri;
+
+pub mod respot;
+pub mod queue;
+pub mod track;
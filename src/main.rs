@@ -1,30 +1,22 @@
-#[macro_use(lazy_static)]
-extern crate lazy_static;
-extern crate regex;
 #[macro_use]
 extern crate log;
 
-use crate::spotify::{new_spotify_client, get_token_auto, SCOPES};
-use crate::config::Config;
+use spotify_mpd::spotify::{new_spotify_client, get_token_auto, SharedSpotify, TokenManager, SCOPES};
+use spotify_mpd::config::{Config, PlayerBackend};
+use spotify_mpd::mpd;
 use rspotify::oauth2::SpotifyOAuth;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use tokio_core::reactor::Core;
 use librespot::core::authentication::Credentials;
 use librespot::core::config::SessionConfig;
 use librespot::core::session::Session;
-use crate::respot::{PlayerEvent, Respot};
+use spotify_mpd::respot::{AudioEngine, LibrespotEngine, PlayerEvent, Respot, SimulatedEngine, VirtualClock};
 use std::sync::{Mutex, Arc};
-use crate::queue::Queue;
+use spotify_mpd::queue::Queue;
+use spotify_mpd::state::StateFile;
 use futures::channel::mpsc;
-
-mod config;
-mod mpd;
-mod spotify;
-mod redirect_uri;
-
-mod respot;
-mod queue;
-mod track;
+use tokio::sync::broadcast;
+use spotify_mpd::catalog::{FixtureCatalog, MetadataCache, MusicCatalog, SpotifyCatalog};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -32,26 +24,41 @@ async fn main() -> Result<()> {
     let mut core = Core::new().unwrap();
 
     let config = Config::new()?;
-    let spotify_config = config.spotify.as_ref().unwrap();
 
-    let mut oauth = SpotifyOAuth::default()
-        .client_id(spotify_config.client_id.as_ref().unwrap())
-        .client_secret(spotify_config.client_secret.as_ref().unwrap())
-        .redirect_uri(&config.get_redirect_uri())
-        .scope(&SCOPES.join(" "))
-        .build();
+    let (catalog, token_manager): (Arc<dyn MusicCatalog>, Option<TokenManager>) = match config.spotify.as_ref() {
+        Some(spotify_config) => {
+            let mut oauth = SpotifyOAuth::default()
+                .client_id(spotify_config.client_id.as_ref().unwrap())
+                .client_secret(spotify_config.client_secret.as_ref().unwrap())
+                .redirect_uri(&config.get_redirect_uri())
+                .scope(&SCOPES.join(" "))
+                .build();
 
-    match get_token_auto(&mut oauth, spotify_config.port.unwrap()).await {
-        Some(token_info) => {
-            let (spotify, _token_expiry) = new_spotify_client(token_info);
+            match get_token_auto(&mut oauth, spotify_config.port.unwrap()).await {
+                Some(token_info) => {
+                    let (spotify, token_expiry) = new_spotify_client(token_info.clone());
+                    let spotify = SharedSpotify::new(spotify);
+                    let token_manager = TokenManager::new(oauth, spotify.clone(), token_info, token_expiry);
+                    let catalog = MetadataCache::new(Box::new(SpotifyCatalog::new(spotify)), config.cache.as_ref());
 
-            let (command_sender, command_receiver) = mpsc::unbounded();
-            let (event_sender, event_receiver) = std::sync::mpsc::channel::<PlayerEvent>();
-            let command_sender_mutex = Arc::new(Mutex::new(command_sender));
-
-            let queue = Arc::new(Queue::new(command_sender_mutex));
-            Queue::start_worker(queue.clone(), event_receiver);
+                    (Arc::new(catalog), Some(token_manager))
+                }
+                None => {
+                    error!("Spotify auth failed");
+                    return Ok(());
+                }
+            }
+        }
+        None => {
+            info!("No Spotify account configured, serving the demo library");
+            (Arc::new(FixtureCatalog::demo()), None)
+        }
+    };
 
+    let create_engine: Box<dyn FnOnce() -> Box<dyn AudioEngine> + Send> = match config.get_player_backend() {
+        PlayerBackend::Librespot => {
+            let spotify_config = config.spotify.as_ref()
+                .ok_or_else(|| anyhow!("The librespot player needs a [spotify] section in the config"))?;
             let session_config = SessionConfig::default();
             let credentials = Credentials::with_password(spotify_config.username.as_ref().unwrap().to_owned(), spotify_config.password.as_ref().unwrap().to_owned());
 
@@ -59,22 +66,57 @@ async fn main() -> Result<()> {
                 .run(Session::connect(session_config, credentials, None, core.handle()))
                 .unwrap();
 
-            let mpd_config = config.mpd.as_ref().unwrap();
-            let mpd_ip = mpd_config.ip.as_ref().unwrap().to_owned();
-            let mpd_port = mpd_config.port.as_ref().unwrap().to_owned();
-            std::thread::spawn(move || {
-                let mut mpd_server = mpd::MpdServer::new(
-                    format!("{}:{}", mpd_ip, mpd_port),
-                    spotify,
-                    queue
-                );
-                mpd_server.run();
-            });
-
-            core.run(futures::compat::Compat::new(Respot::new(session, command_receiver, event_sender))).unwrap();
+            Box::new(move || Box::new(LibrespotEngine::new(session)) as Box<dyn AudioEngine>)
+        }
+        PlayerBackend::Simulated => {
+            let clock = VirtualClock::new();
+            clock.follow_real_time();
+
+            Box::new(move || Box::new(SimulatedEngine::new(clock)) as Box<dyn AudioEngine>)
         }
-        None => error!("Spotify auth failed"),
+    };
+
+    let (command_sender, command_receiver) = mpsc::unbounded();
+    let (event_sender, event_receiver) = std::sync::mpsc::channel::<PlayerEvent>();
+    let command_sender_mutex = Arc::new(Mutex::new(command_sender));
+
+    let (event_bus, _) = broadcast::channel(100);
+
+    let queue = Arc::new(Queue::new(command_sender_mutex, event_bus.clone()));
+    Queue::start_worker(queue.clone(), event_receiver);
+
+    let mpd_config = config.mpd.as_ref().unwrap();
+    let state_file = mpd_config.state_file.as_ref().map(|path| StateFile::new(path, queue.clone()));
+    if let Some(state_file) = &state_file {
+        state_file.restore(mpd_config.resume_playback.unwrap_or(false));
+    }
+    // Subscribe after restoring, so the restore itself doesn't write the file back
+    let state_events = event_bus.subscribe();
+
+    let mpd_ip = mpd_config.ip.as_ref().unwrap().to_owned();
+    let mpd_port = mpd_config.port.as_ref().unwrap().to_owned();
+    let mut mpd_server = mpd::MpdServer::new(
+        format!("{}:{}", mpd_ip, mpd_port),
+        catalog,
+        queue,
+        event_bus
+    );
+    // Everything async runs on the worker threads of the main runtime, while this thread
+    // drives the player below
+    tokio::spawn(async move { mpd_server.run().await });
+    if let Some(token_manager) = token_manager {
+        tokio::spawn(token_manager.run());
+    }
+    if let Some(state_file) = state_file.clone() {
+        tokio::spawn(state_file.run(state_events));
     }
 
+    let respot = Respot::new(create_engine, command_receiver, event_sender).on_shutdown(move || {
+        if let Some(state_file) = state_file {
+            state_file.save();
+        }
+    });
+    core.run(futures::compat::Compat::new(respot)).unwrap();
+
     Ok(())
 }
@@ -0,0 +1,60 @@
+use core::fmt;
+
+/// Errors a command can answer with, each one maps to an MPD `ACK` error code
+#[derive(Debug, Clone, PartialEq)]
+pub enum MpdError {
+    Arg(String),
+    Password(String),
+    Permission(String),
+    Unknown(String),
+    NoExist(String),
+    /// The player is not in a state the command works in, like seeking while stopped
+    PlayerSync(String),
+    System(String),
+    Exist(String),
+}
+
+impl MpdError {
+    pub fn system<E: fmt::Display>(error: E) -> Self {
+        MpdError::System(error.to_string())
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            MpdError::Arg(_) => 2,
+            MpdError::Password(_) => 3,
+            MpdError::Permission(_) => 4,
+            MpdError::Unknown(_) => 5,
+            MpdError::NoExist(_) => 50,
+            MpdError::PlayerSync(_) => 55,
+            MpdError::System(_) => 52,
+            MpdError::Exist(_) => 56,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            MpdError::Arg(message)
+            | MpdError::Password(message)
+            | MpdError::Permission(message)
+            | MpdError::Unknown(message)
+            | MpdError::NoExist(message)
+            | MpdError::PlayerSync(message)
+            | MpdError::System(message)
+            | MpdError::Exist(message) => message,
+        }
+    }
+
+    /// Formats the error as the `ACK [code@list_index] {command} message` response line
+    pub fn to_ack(&self, list_index: usize, command: &str) -> String {
+        format!("ACK [{}@{}] {{{}}} {}", self.code(), list_index, command, self.message())
+    }
+}
+
+impl fmt::Display for MpdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for MpdError {}
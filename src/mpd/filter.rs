@@ -0,0 +1,485 @@
+use crate::mpd::error::MpdError;
+use crate::mpd::request::Arguments;
+use crate::track::Track;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::{Chars, FromStr};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tag {
+    Any,
+    File,
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    Track,
+    Date,
+}
+
+impl Tag {
+    pub fn name(&self) -> &str {
+        match *self {
+            Tag::Any => "any",
+            Tag::File => "file",
+            Tag::Artist => "Artist",
+            Tag::AlbumArtist => "AlbumArtist",
+            Tag::Album => "Album",
+            Tag::Title => "Title",
+            Tag::Track => "Track",
+            Tag::Date => "Date",
+        }
+    }
+
+    pub fn values(&self, track: &Track) -> Vec<String> {
+        match *self {
+            Tag::Any => [
+                Tag::File,
+                Tag::Artist,
+                Tag::AlbumArtist,
+                Tag::Album,
+                Tag::Title,
+                Tag::Track,
+                Tag::Date,
+            ].iter().flat_map(|tag| tag.values(track)).collect(),
+            Tag::File => track.id.iter().cloned().collect(),
+            Tag::Artist => track.artists.clone(),
+            Tag::AlbumArtist => track.album_artists.clone(),
+            Tag::Album => vec![track.album.clone()],
+            Tag::Title => vec![track.title.clone()],
+            Tag::Track => vec![track.track_number.to_string()],
+            Tag::Date => vec![track.date.clone()],
+        }
+    }
+}
+
+impl FromStr for Tag {
+    type Err = MpdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(Tag::Any),
+            "file" => Ok(Tag::File),
+            "artist" => Ok(Tag::Artist),
+            "albumartist" => Ok(Tag::AlbumArtist),
+            "album" => Ok(Tag::Album),
+            "title" => Ok(Tag::Title),
+            "track" => Ok(Tag::Track),
+            "date" => Ok(Tag::Date),
+            _ => Err(MpdError::Arg(format!("Unknown tag type: {}", s))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Equals,
+    Contains,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub tag: Tag,
+    pub operator: Operator,
+    pub value: String,
+    pub negated: bool,
+}
+
+impl Condition {
+    fn matches(&self, track: &Track, case_sensitive: bool) -> bool {
+        let value = if case_sensitive { self.value.clone() } else { self.value.to_lowercase() };
+        let found = self.tag.values(track).iter().any(|tag_value| {
+            let tag_value = if case_sensitive { tag_value.clone() } else { tag_value.to_lowercase() };
+            match self.operator {
+                // Spotify dates are often just a year, clients ask for the year of full ones too
+                Operator::Equals if self.tag == Tag::Date => tag_value.get(..value.len()) == Some(value.as_str())
+                    && tag_value[value.len()..].chars().next().is_none_or(|c| c == '-'),
+                Operator::Equals => tag_value == value,
+                Operator::Contains => tag_value.contains(&value),
+            }
+        });
+
+        found != self.negated
+    }
+}
+
+/// A parsed `find`/`search` filter, either from the legacy `TAG VALUE` pairs or a
+/// filter expression like `((artist == "x") AND (album == "y"))`, together with the
+/// `sort`, `window` and `group` options following it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub conditions: Vec<Condition>,
+    pub window: Option<Range<usize>>,
+    pub group: Vec<Tag>,
+}
+
+impl Filter {
+    /// Parses the filter from `args`, `exact` decides what the legacy syntax compares with
+    pub fn parse(args: &Arguments, exact: bool) -> Result<Self, MpdError> {
+        let mut filter = Filter::default();
+        let args: Vec<&str> = args.iter().collect();
+        let mut index = 0;
+
+        while index < args.len() {
+            match args[index].to_lowercase().as_str() {
+                "sort" => {
+                    // Results are kept in the order Spotify ranked them
+                    Self::option_value(&args, index)?;
+                    index += 2;
+                }
+                "window" => {
+                    filter.window = Some(Self::parse_window(Self::option_value(&args, index)?)?);
+                    index += 2;
+                }
+                "group" => {
+                    filter.group.push(Tag::from_str(Self::option_value(&args, index)?)?);
+                    index += 2;
+                }
+                _ if args[index].starts_with('(') => {
+                    filter.conditions.extend(ExpressionParser::new(args[index]).parse()?);
+                    index += 1;
+                }
+                tag => {
+                    let value = args.get(index + 1)
+                        .ok_or_else(|| MpdError::Arg("Missing value for tag".to_owned()))?;
+                    filter.conditions.push(Condition {
+                        tag: Tag::from_str(tag)?,
+                        operator: if exact { Operator::Equals } else { Operator::Contains },
+                        value: (*value).to_owned(),
+                        negated: false,
+                    });
+                    index += 2;
+                }
+            }
+        }
+
+        Ok(filter)
+    }
+
+    pub fn matches(&self, track: &Track, case_sensitive: bool) -> bool {
+        self.conditions.iter().all(|condition| condition.matches(track, case_sensitive))
+    }
+
+    /// Builds the Spotify search query for the conditions Spotify can answer,
+    /// the full filter is applied to the results afterwards.
+    pub fn to_spotify_query(&self) -> String {
+        self.conditions
+            .iter()
+            .filter(|condition| !condition.negated)
+            .filter_map(|condition| {
+                let value = condition.value.replace('"', "");
+                match condition.tag {
+                    Tag::Any => Some(value),
+                    Tag::Artist | Tag::AlbumArtist => Some(format!("artist:\"{}\"", value)),
+                    Tag::Album => Some(format!("album:\"{}\"", value)),
+                    Tag::Title => Some(format!("track:\"{}\"", value)),
+                    Tag::Date => value.get(..4).map(|year| format!("year:{}", year)),
+                    Tag::File | Tag::Track => None,
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// The track ids the filter asks for directly with `file`
+    pub fn files(&self) -> Vec<&str> {
+        self.conditions
+            .iter()
+            .filter(|condition| condition.tag == Tag::File && condition.operator == Operator::Equals && !condition.negated)
+            .map(|condition| condition.value.as_str())
+            .collect()
+    }
+
+    fn option_value<'a>(args: &[&'a str], index: usize) -> Result<&'a str, MpdError> {
+        args.get(index + 1)
+            .cloned()
+            .ok_or_else(|| MpdError::Arg(format!("Missing value for {}", args[index])))
+    }
+
+    fn parse_window(window: &str) -> Result<Range<usize>, MpdError> {
+        let mut parts = window.splitn(2, ':');
+        let start = parts.next().unwrap_or("");
+        let end = parts.next().ok_or_else(|| MpdError::Arg(format!("Invalid window: {}", window)))?;
+        let start = usize::from_str(start).map_err(|_| MpdError::Arg(format!("Invalid window: {}", window)))?;
+        let end = usize::from_str(end).map_err(|_| MpdError::Arg(format!("Invalid window: {}", window)))?;
+
+        Ok(start..end)
+    }
+}
+
+/// Recursive descent parser for MPD 0.21 filter expressions, supporting `==`, `!=`,
+/// `contains`, negation with `!` and combining expressions with `AND`.
+struct ExpressionParser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> ExpressionParser<'a> {
+    fn new(expression: &'a str) -> Self {
+        Self {
+            chars: expression.chars().peekable(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<Condition>, MpdError> {
+        let conditions = self.parse_expression()?;
+        self.skip_whitespace();
+        if self.chars.next().is_some() {
+            return Err(MpdError::Arg("Unparsed garbage after expression".to_owned()));
+        }
+
+        Ok(conditions)
+    }
+
+    fn parse_expression(&mut self) -> Result<Vec<Condition>, MpdError> {
+        self.skip_whitespace();
+        self.expect('(')?;
+        self.skip_whitespace();
+
+        let conditions = match self.chars.peek() {
+            Some('!') => {
+                self.chars.next();
+                let mut conditions = self.parse_expression()?;
+                if conditions.len() != 1 {
+                    return Err(MpdError::Arg("Only single expressions can be negated".to_owned()));
+                }
+                conditions[0].negated = !conditions[0].negated;
+                conditions
+            }
+            Some('(') => {
+                let mut conditions = self.parse_expression()?;
+                loop {
+                    self.skip_whitespace();
+                    if self.chars.peek() == Some(&')') {
+                        break;
+                    }
+                    let word = self.parse_word();
+                    if word != "AND" {
+                        return Err(MpdError::Arg("'AND' expected".to_owned()));
+                    }
+                    conditions.extend(self.parse_expression()?);
+                }
+                conditions
+            }
+            _ => vec![self.parse_condition()?],
+        };
+
+        self.skip_whitespace();
+        self.expect(')')?;
+
+        Ok(conditions)
+    }
+
+    fn parse_condition(&mut self) -> Result<Condition, MpdError> {
+        let tag = Tag::from_str(&self.parse_word())?;
+        self.skip_whitespace();
+
+        let (operator, negated) = match self.parse_operator().as_str() {
+            "==" => (Operator::Equals, false),
+            "!=" => (Operator::Equals, true),
+            "contains" => (Operator::Contains, false),
+            operator => return Err(MpdError::Arg(format!("Unknown filter operator: {}", operator))),
+        };
+        self.skip_whitespace();
+
+        Ok(Condition {
+            tag,
+            operator,
+            value: self.parse_quoted()?,
+            negated,
+        })
+    }
+
+    fn parse_word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_alphanumeric() || c == '_' || c == '-') {
+                break;
+            }
+            word.push(c);
+            self.chars.next();
+        }
+
+        word
+    }
+
+    fn parse_operator(&mut self) -> String {
+        match self.chars.peek() {
+            Some('=') | Some('!') => {
+                let mut operator = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c != '=' && c != '!' && c != '~' {
+                        break;
+                    }
+                    operator.push(c);
+                    self.chars.next();
+                }
+                operator
+            }
+            _ => self.parse_word(),
+        }
+    }
+
+    fn parse_quoted(&mut self) -> Result<String, MpdError> {
+        let quote = match self.chars.next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => return Err(MpdError::Arg("Quoted string expected".to_owned())),
+        };
+
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                Some('\\') => match self.chars.next() {
+                    Some(escaped) => value.push(escaped),
+                    None => break,
+                },
+                Some(c) if c == quote => return Ok(value),
+                Some(c) => value.push(c),
+                None => break,
+            }
+        }
+
+        Err(MpdError::Arg("Closing quote not found".to_owned()))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), MpdError> {
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(MpdError::Arg(format!("'{}' expected", expected))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track() -> Track {
+        Track {
+            id: Some("4uLU6hMCjMI75M1A2tKUQC".to_owned()),
+            title: "Here Comes the Sun".to_owned(),
+            track_number: 7,
+            disc_number: 1,
+            duration: 185_000,
+            artists: vec!["The Beatles".to_owned()],
+            album: "Abbey Road".to_owned(),
+            album_id: None,
+            album_artists: vec!["The Beatles".to_owned()],
+            url: String::new(),
+            added_at: None,
+            date: "1969-09-26".to_owned(),
+        }
+    }
+
+    fn parse(args: &[&str]) -> Result<Filter, MpdError> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+        Filter::parse(&Arguments::from(args), true)
+    }
+
+    fn condition(tag: Tag, operator: Operator, value: &str, negated: bool) -> Condition {
+        Condition {
+            tag,
+            operator,
+            value: value.to_owned(),
+            negated,
+        }
+    }
+
+    #[test]
+    fn legacy_pairs() {
+        let filter = parse(&["artist", "The Beatles", "album", "Abbey Road"]).unwrap();
+
+        assert_eq!(filter.conditions, vec![
+            condition(Tag::Artist, Operator::Equals, "The Beatles", false),
+            condition(Tag::Album, Operator::Equals, "Abbey Road", false),
+        ]);
+        assert!(filter.matches(&track(), true));
+        assert!(parse(&["artist"]).is_err());
+    }
+
+    #[test]
+    fn quoted_values() {
+        let filter = parse(&[r#"(title == 'Here Comes the Sun')"#]).unwrap();
+        assert_eq!(filter.conditions, vec![condition(Tag::Title, Operator::Equals, "Here Comes the Sun", false)]);
+
+        let filter = parse(&[r#"(album contains "Say \"hi\"")"#]).unwrap();
+        assert_eq!(filter.conditions, vec![condition(Tag::Album, Operator::Contains, r#"Say "hi""#, false)]);
+    }
+
+    #[test]
+    fn negation() {
+        let filter = parse(&[r#"(artist != "The Rolling Stones")"#]).unwrap();
+        assert_eq!(filter.conditions, vec![condition(Tag::Artist, Operator::Equals, "The Rolling Stones", true)]);
+        assert!(filter.matches(&track(), true));
+
+        let filter = parse(&[r#"(!(artist == "The Beatles"))"#]).unwrap();
+        assert_eq!(filter.conditions, vec![condition(Tag::Artist, Operator::Equals, "The Beatles", true)]);
+        assert!(!filter.matches(&track(), true));
+    }
+
+    #[test]
+    fn nested_and() {
+        let filter = parse(&[r#"((artist == "The Beatles") AND ((album contains "Abbey") AND (track == "7")))"#]).unwrap();
+
+        assert_eq!(filter.conditions, vec![
+            condition(Tag::Artist, Operator::Equals, "The Beatles", false),
+            condition(Tag::Album, Operator::Contains, "Abbey", false),
+            condition(Tag::Track, Operator::Equals, "7", false),
+        ]);
+        assert!(filter.matches(&track(), true));
+        assert!(!parse(&[r#"((artist == "The Beatles") AND (track == "8"))"#]).unwrap().matches(&track(), true));
+    }
+
+    #[test]
+    fn case_sensitivity() {
+        let filter = Filter::parse(&Arguments::from(vec!["artist".to_owned(), "the beatles".to_owned()]), false).unwrap();
+
+        assert!(filter.matches(&track(), false));
+        assert!(!filter.matches(&track(), true));
+    }
+
+    #[test]
+    fn date_matches_on_its_prefix() {
+        assert!(parse(&["date", "1969"]).unwrap().matches(&track(), true));
+        assert!(parse(&["date", "1969-09"]).unwrap().matches(&track(), true));
+        assert!(!parse(&["date", "196"]).unwrap().matches(&track(), true));
+        assert!(!parse(&["date", "1970"]).unwrap().matches(&track(), true));
+    }
+
+    #[test]
+    fn options() {
+        let filter = parse(&["artist", "x", "sort", "Title", "window", "2:5", "group", "album"]).unwrap();
+
+        assert_eq!(filter.window, Some(2..5));
+        assert_eq!(filter.group, vec![Tag::Album]);
+        assert!(parse(&["artist", "x", "window", "2"]).is_err());
+        assert!(parse(&["artist", "x", "window"]).is_err());
+    }
+
+    #[test]
+    fn bad_expressions() {
+        let expressions = [
+            r#"(artist == "x""#,
+            r#"(artist == "x)"#,
+            r#"(artist == x)"#,
+            r#"(artist ~= "x")"#,
+            r#"(colour == "x")"#,
+            r#"((artist == "x") OR (album == "y"))"#,
+            r#"(!((artist == "x") AND (album == "y")))"#,
+            r#"(artist == "x") trailing"#,
+        ];
+
+        for expression in expressions.iter() {
+            assert!(parse(&[*expression]).is_err(), "{} was accepted", expression);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::mpd::error::MpdError;
+use crate::mpd::Client;
+use crate::track::Track;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+const PLAYLISTS: &str = "Playlists";
+const LIKED_SONGS: &str = "Liked Songs";
+const ALBUMS: &str = "Albums";
+const ARTISTS: &str = "Artists";
+const PODCASTS: &str = "Podcasts";
+
+/// The directories at the root of the virtual library
+const ROOT_DIRECTORIES: [&str; 5] = [PLAYLISTS, LIKED_SONGS, ALBUMS, ARTISTS, PODCASTS];
+
+/// An entry of the virtual library, directories are identified by their full path
+/// and songs by the Spotify id in their `file` tag.
+pub enum LibraryEntry {
+    Directory(String),
+    Song(Track),
+}
+
+impl LibraryEntry {
+    /// The entry as `lsinfo`/`listallinfo` sends it
+    pub fn to_mpd_format(&self) -> Vec<String> {
+        match self {
+            LibraryEntry::Directory(path) => vec![
+                format!("directory: {}", path),
+                // Spotify doesn't tell us when the library changed
+                "Last-Modified: 1970-01-01T00:00:00Z".to_owned(),
+            ],
+            LibraryEntry::Song(track) => track.to_mpd_format(),
+        }
+    }
+
+    /// The entry as `listall` sends it
+    pub fn to_mpd_uri(&self) -> String {
+        match self {
+            LibraryEntry::Directory(path) => format!("directory: {}", path),
+            LibraryEntry::Song(track) => format!("file: {}", track.id.as_ref().unwrap()),
+        }
+    }
+
+    /// The entry as `listfiles` sends it, relative to the listed directory
+    pub fn to_mpd_file(&self) -> String {
+        match self {
+            LibraryEntry::Directory(path) => format!("directory: {}", path.rsplit('/').next().unwrap_or(path)),
+            LibraryEntry::Song(track) => format!("file: {}", track.id.as_ref().unwrap()),
+        }
+    }
+}
+
+/// Lists the entries directly inside the virtual directory at `path`
+pub async fn list_directory(client: &Arc<Client>, path: &str) -> Result<Vec<LibraryEntry>, MpdError> {
+    let path = path.trim_matches('/');
+    let components: Vec<&str> = path.split('/').filter(|component| !component.is_empty()).collect();
+
+    let catalog = &client.catalog;
+    let entries = match components.as_slice() {
+        [] => ROOT_DIRECTORIES
+            .iter()
+            .map(|directory| LibraryEntry::Directory((*directory).to_owned()))
+            .collect(),
+        [PLAYLISTS] => {
+            let playlists = catalog.user_playlists().await.map_err(MpdError::system)?;
+            directories(path, playlists.iter().map(|playlist| playlist.name.as_str()))
+        }
+        [PLAYLISTS, name] => {
+            let playlists = catalog.user_playlists().await.map_err(MpdError::system)?;
+            let playlist = find_by_name(playlists, name, |playlist| &playlist.name)?;
+            songs(catalog.playlist_tracks(&playlist).await.map_err(MpdError::system)?)
+        }
+        [LIKED_SONGS] => songs(catalog.saved_tracks().await.map_err(MpdError::system)?),
+        [ALBUMS] => {
+            let albums = catalog.saved_albums().await.map_err(MpdError::system)?;
+            directories(path, albums.iter().map(|album| album.name.as_str()))
+        }
+        [ALBUMS, name] => {
+            let albums = catalog.saved_albums().await.map_err(MpdError::system)?;
+            let album = find_by_name(albums, name, |album| &album.name)?;
+            songs(catalog.album_tracks(&album).await.map_err(MpdError::system)?)
+        }
+        [ARTISTS] => {
+            let artists = catalog.followed_artists().await.map_err(MpdError::system)?;
+            directories(path, artists.iter().map(|artist| artist.name.as_str()))
+        }
+        [ARTISTS, artist] => {
+            let artists = catalog.followed_artists().await.map_err(MpdError::system)?;
+            let artist = find_by_name(artists, artist, |artist| &artist.name)?;
+            let albums = catalog.artist_albums(&artist).await.map_err(MpdError::system)?;
+            directories(path, albums.iter().map(|album| album.name.as_str()))
+        }
+        [ARTISTS, artist, name] => {
+            let artists = catalog.followed_artists().await.map_err(MpdError::system)?;
+            let artist = find_by_name(artists, artist, |artist| &artist.name)?;
+            let albums = catalog.artist_albums(&artist).await.map_err(MpdError::system)?;
+            let album = find_by_name(albums, name, |album| &album.name)?;
+            songs(catalog.album_tracks(&album).await.map_err(MpdError::system)?)
+        }
+        [PODCASTS] => {
+            let shows = catalog.saved_shows().await.map_err(MpdError::system)?;
+            directories(path, shows.iter().map(|show| show.name.as_str()))
+        }
+        [PODCASTS, name] => {
+            let shows = catalog.saved_shows().await.map_err(MpdError::system)?;
+            let show = find_by_name(shows, name, |show| &show.name)?;
+            songs(catalog.show_episodes(&show).await.map_err(MpdError::system)?)
+        }
+        _ => return Err(no_such_directory()),
+    };
+
+    Ok(entries)
+}
+
+/// Lists everything below the virtual directory at `path`, each directory followed by its contents.
+pub async fn walk_directory(client: &Arc<Client>, path: &str) -> Result<Vec<LibraryEntry>, MpdError> {
+    let mut entries = vec![];
+    let mut visited = HashSet::new();
+    let mut pending = list_directory(client, path).await?;
+    pending.reverse();
+
+    while let Some(entry) = pending.pop() {
+        if let LibraryEntry::Directory(ref directory) = entry {
+            // Names that only differ by their slashes end up at the same path, list it once
+            if !visited.insert(directory.clone()) {
+                continue;
+            }
+            let mut children = list_directory(client, directory).await?;
+            children.reverse();
+            pending.extend(children);
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Whether `uri` names a virtual directory rather than a song, the root included
+pub fn is_directory(uri: &str) -> bool {
+    match uri.trim_matches('/').split('/').next() {
+        Some("") | None => true,
+        Some(root) => ROOT_DIRECTORIES.contains(&root),
+    }
+}
+
+/// The songs anywhere below the virtual directory at `path`, in the order they are listed
+pub async fn directory_songs(client: &Arc<Client>, path: &str) -> Result<Vec<Track>, MpdError> {
+    Ok(walk_directory(client, path)
+        .await?
+        .into_iter()
+        .filter_map(|entry| match entry {
+            LibraryEntry::Song(track) => Some(track),
+            LibraryEntry::Directory(_) => None,
+        })
+        .collect())
+}
+
+/// Finds the item a directory was named after
+fn find_by_name<T>(items: Vec<T>, name: &str, item_name: impl Fn(&T) -> &String) -> Result<T, MpdError> {
+    items
+        .into_iter()
+        .find(|item| directory_name(item_name(item)) == name)
+        .ok_or_else(no_such_directory)
+}
+
+fn directories<'a>(parent: &str, names: impl Iterator<Item=&'a str>) -> Vec<LibraryEntry> {
+    names
+        .map(|name| LibraryEntry::Directory(format!("{}/{}", parent, directory_name(name))))
+        .collect()
+}
+
+fn songs(tracks: Vec<Track>) -> Vec<LibraryEntry> {
+    tracks
+        .into_iter()
+        .filter(Track::is_playable)
+        .map(LibraryEntry::Song)
+        .collect()
+}
+
+/// Spotify names may contain slashes, which would split them into several path components
+fn directory_name(name: &str) -> String {
+    name.replace('/', "-")
+}
+
+fn no_such_directory() -> MpdError {
+    MpdError::NoExist("No such directory".to_owned())
+}
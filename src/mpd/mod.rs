@@ -1,18 +1,26 @@
-use std::net::{TcpListener, TcpStream};
-use net2::TcpStreamExt;
-use std::thread;
-use std::io::{Write, BufReader, BufRead, Read};
-use rspotify::client::Spotify;
-use regex::Regex;
-use anyhow::{Result, Error};
-use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, WriteHalf};
+use tokio::sync::broadcast;
+use tokio_util::codec::{FramedRead, LinesCodec};
+use futures::StreamExt;
+use anyhow::{anyhow, Result, Error};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::collections::BTreeSet;
+use std::time::Duration;
 use core::fmt;
-use bus::{Bus, BusReader};
 
 use crate::mpd::mpd_commands::*;
+use crate::mpd::request::{Request, Arguments};
+use crate::mpd::error::MpdError;
 use crate::queue::Queue;
+use crate::catalog::MusicCatalog;
 
 mod mpd_commands;
+mod request;
+mod error;
+mod filter;
+mod library;
 
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
 pub enum SubsystemEvent {
@@ -20,6 +28,7 @@ pub enum SubsystemEvent {
     Update,
     StoragePlaylist,
     Playlist,
+    Player,
     Mixer,
     Output,
     Options,
@@ -29,64 +38,116 @@ pub enum SubsystemEvent {
     Message,
 }
 
+impl SubsystemEvent {
+    const ALL: [SubsystemEvent; 12] = [
+        SubsystemEvent::Database,
+        SubsystemEvent::Update,
+        SubsystemEvent::StoragePlaylist,
+        SubsystemEvent::Playlist,
+        SubsystemEvent::Player,
+        SubsystemEvent::Mixer,
+        SubsystemEvent::Output,
+        SubsystemEvent::Options,
+        SubsystemEvent::Partition,
+        SubsystemEvent::Sticker,
+        SubsystemEvent::Subscription,
+        SubsystemEvent::Message,
+    ];
+}
+
 impl fmt::Display for SubsystemEvent {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match *self {
+            SubsystemEvent::StoragePlaylist => write!(f, "stored_playlist"),
+            _ => write!(f, "{}", format!("{:?}", self).to_lowercase()),
+        }
+    }
+}
+
+impl FromStr for SubsystemEvent {
+    type Err = MpdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "database" => Ok(SubsystemEvent::Database),
+            "update" => Ok(SubsystemEvent::Update),
+            "stored_playlist" => Ok(SubsystemEvent::StoragePlaylist),
+            "playlist" => Ok(SubsystemEvent::Playlist),
+            "player" => Ok(SubsystemEvent::Player),
+            "mixer" => Ok(SubsystemEvent::Mixer),
+            "output" => Ok(SubsystemEvent::Output),
+            "options" => Ok(SubsystemEvent::Options),
+            "partition" => Ok(SubsystemEvent::Partition),
+            "sticker" => Ok(SubsystemEvent::Sticker),
+            "subscription" => Ok(SubsystemEvent::Subscription),
+            "message" => Ok(SubsystemEvent::Message),
+            _ => Err(MpdError::Arg(format!("Unrecognized idle event: {}", s))),
+        }
     }
 }
 
 pub struct Client {
-    spotify: Arc<Spotify>,
+    catalog: Arc<dyn MusicCatalog>,
     queue: Arc<Queue>,
-    event_bus: Arc<Mutex<Bus<SubsystemEvent>>>,
+    event_bus: broadcast::Sender<SubsystemEvent>,
 }
 
 impl Client {
-    fn new(spotify: Arc<Spotify>, queue: Arc<Queue>) -> Self {
+    fn new(catalog: Arc<dyn MusicCatalog>, queue: Arc<Queue>, event_bus: broadcast::Sender<SubsystemEvent>) -> Self {
         Self {
-            spotify,
+            catalog,
             queue,
-            event_bus: Arc::new(Mutex::new(Bus::new(100))),
+            event_bus,
         }
     }
+
+    fn notify(&self, event: SubsystemEvent) {
+        notify(&self.event_bus, event);
+    }
 }
 
-pub(crate) struct MpdServer {
+/// Tells every connection that a subsystem changed
+pub fn notify(event_bus: &broadcast::Sender<SubsystemEvent>, event: SubsystemEvent) {
+    // Sending only fails when no clients are connected
+    let _ = event_bus.send(event);
+}
+
+pub struct MpdServer {
     host: String,
     client: Arc<Client>,
 }
 
 impl MpdServer {
-    pub fn new(host: String, spotify: Arc<Spotify>, queue: Arc<Queue>) -> Self {
+    pub fn new(host: String, catalog: Arc<dyn MusicCatalog>, queue: Arc<Queue>, event_bus: broadcast::Sender<SubsystemEvent>) -> Self {
         Self {
             host,
-            client: Arc::new(Client::new(spotify, queue)),
+            client: Arc::new(Client::new(catalog, queue, event_bus)),
         }
     }
 
-    pub fn run(&mut self) {
-        let listener = TcpListener::bind(self.host.to_owned()).unwrap();
+    pub async fn run(&mut self) {
+        let listener = TcpListener::bind(self.host.to_owned()).await.unwrap();
         println!("Server listening on {}", self.host);
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!("New connection: {}", stream.peer_addr().unwrap());
+        self.serve(listener).await;
+    }
+
+    /// Accepts connections and serves every client as a task on the current runtime
+    pub async fn serve(&mut self, mut listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, address)) => {
+                    println!("New connection: {}", address);
 
-                    let mut handler = MpdRequestHandler::new(Arc::clone(&self.client));
-                    let event_receiver = self.client.event_bus.lock().unwrap().add_rx();
-                    thread::spawn(move || {
-                        handler.handle_client(stream, event_receiver);
-                    });
+                    let handler = MpdRequestHandler::new(Arc::clone(&self.client));
+                    let event_receiver = self.client.event_bus.subscribe();
+                    tokio::spawn(handler.handle_client(stream, event_receiver));
                 }
                 Err(e) => {
                     println!("Error: {}", e);
                 }
             }
         }
-
-        // close the socket server
-        drop(listener);
     }
 }
 
@@ -96,186 +157,354 @@ lazy_static! {
         Box::new(StatsCommand),
         Box::new(ListPlaylistsCommand),
         Box::new(ListPlaylistInfoCommand),
+        Box::new(ListPlaylistCommand),
+        Box::new(LoadCommand),
+        Box::new(PlaylistAddCommand),
+        Box::new(PlaylistClearCommand),
+        Box::new(PlaylistDeleteCommand),
+        Box::new(PlaylistMoveCommand),
+        Box::new(RenameCommand),
+        Box::new(RmCommand),
+        Box::new(SaveCommand),
         Box::new(AddCommand),
         Box::new(PlayCommand),
+        Box::new(PlayIdCommand),
         Box::new(PauseCommand),
+        Box::new(StopCommand),
         Box::new(NextCommand),
         Box::new(PrevCommand),
         Box::new(ClearCommand),
         Box::new(PlaylistInfoCommand),
+        Box::new(PlaylistIdCommand),
+        Box::new(PlChangesCommand),
+        Box::new(PlChangesPosIdCommand),
         Box::new(CurrentSongCommand),
         Box::new(SetVolCommand),
         Box::new(VolumeCommand),
+        Box::new(DeleteCommand),
         Box::new(DeleteIdCommand),
+        Box::new(SeekCommand),
+        Box::new(SeekIdCommand),
+        Box::new(SeekCurCommand),
+        Box::new(RepeatCommand),
+        Box::new(RandomCommand),
+        Box::new(SingleCommand),
+        Box::new(ConsumeCommand),
+        Box::new(MoveCommand),
+        Box::new(MoveIdCommand),
+        Box::new(SwapCommand),
+        Box::new(SwapIdCommand),
+        Box::new(ShuffleCommand),
         Box::new(UrlHandlersCommand),
         Box::new(OutputsCommand),
         Box::new(DecodersCommand),
-        Box::new(TagTypesCommand),
+        Box::new(SearchCommand),
+        Box::new(FindCommand),
+        Box::new(SearchAddCommand),
+        Box::new(FindAddCommand),
+        Box::new(ListCommand),
+        Box::new(CountCommand),
+        Box::new(LsInfoCommand),
+        Box::new(ListAllCommand),
+        Box::new(ListAllInfoCommand),
+        Box::new(ListFilesCommand),
+        Box::new(PingCommand),
+        Box::new(CommandsCommand),
+        Box::new(NotCommandsCommand),
     ];
 }
 
+/// How long a client gets to take in a response before it is disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Longer request lines close the connection, so a client can't make us buffer without limit
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Commands that change the state of a single connection and are handled by it directly
+const CONNECTION_COMMANDS: [&str; 4] = ["close", "idle", "noidle", "tagtypes"];
+
+/// The tags we can send, each connection can enable and disable them with `tagtypes`
+const TAG_TYPES: [&str; 12] = [
+    "Artist",
+    "ArtistSort",
+    "Album",
+    "AlbumSort",
+    "AlbumArtist",
+    "AlbumArtistSort",
+    "Title",
+    "Track",
+    "Name",
+    "Genre",
+    "Date",
+    "Disc",
+];
+
 struct MpdRequestHandler {
     client: Arc<Client>,
-    idle: bool,
-    subsystems_changed: Vec<SubsystemEvent>
+    /// The subsystems the client is idling on, an empty list means all of them
+    idle: Option<Vec<SubsystemEvent>>,
+    /// Each subsystem is kept once, however often it changed before the client idles
+    subsystems_changed: BTreeSet<SubsystemEvent>,
+    command_list: Option<Vec<String>>,
+    /// Whether the current command list was started with `command_list_ok_begin`
+    command_list_ok: bool,
+    tag_types: Vec<&'static str>,
+    closed: bool,
 }
 
 impl MpdRequestHandler {
     pub fn new(client: Arc<Client>) -> Self {
         Self {
             client,
-            idle: false,
-            subsystems_changed: vec![]
+            idle: None,
+            subsystems_changed: BTreeSet::new(),
+            command_list: None,
+            command_list_ok: false,
+            tag_types: TAG_TYPES.to_vec(),
+            closed: false,
         }
     }
 
-    #[tokio::main]
-    async fn handle_client(&mut self, mut stream: TcpStream, mut event_receiver: BusReader<SubsystemEvent>) {
-        let welcome = b"OK MPD 0.21.11\n";
-        stream.write_all(welcome).expect("Unable to send OK msg");
-        self.enable_timeout(&mut stream);
+    async fn handle_client(mut self, stream: TcpStream, mut event_receiver: broadcast::Receiver<SubsystemEvent>) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
 
-        loop {
-            if let Ok(event) = event_receiver.try_recv() {
-                self.subsystems_changed.push(event);
-                if self.idle {
-                    self.send_subsystem_changed(&mut stream);
-                }
-            }
+        if Self::write(&mut writer, "OK MPD 0.21.11\n").await.is_err() {
+            return;
+        }
 
-            let mut command_list = self.get_command_list(&mut stream);
-
-            if !command_list.is_empty() {
-                let first_command = command_list.first().unwrap().to_owned();
-                if first_command == "idle" {
-                    println!("-> {:?}", command_list);
-                    if !self.subsystems_changed.is_empty() {
-                        self.send_subsystem_changed(&mut stream);
-                    } else {
-                        self.idle = true;
-                        self.disable_timeout(&mut stream);
+        loop {
+            let result = tokio::select! {
+                line = lines.next() => match line {
+                    Some(Ok(line)) => self.handle_line(&mut writer, line.trim().to_owned()).await,
+                    // The client hung up or sent something that is not a line of text or too long
+                    _ => break,
+                },
+                event = event_receiver.recv() => match event {
+                    Ok(event) => self.handle_subsystem_events(&mut writer, vec![event]).await,
+                    // We can't tell what the missed events were, so anything may have changed
+                    Err(broadcast::RecvError::Lagged(skipped)) => {
+                        debug!("Connection lagged behind by {} events", skipped);
+                        self.handle_subsystem_events(&mut writer, SubsystemEvent::ALL.to_vec()).await
                     }
-                    command_list.remove(0);
-                } else if first_command == "noidle" {
-                    self.idle = false;
-                    self.enable_timeout(&mut stream);
-                }
-                if !self.idle {
-                    self.run_commands(&mut stream, command_list).await;
-                }
+                    Err(broadcast::RecvError::Closed) => break,
+                },
+            };
+
+            if result.is_err() || self.closed {
+                break;
             }
-        } // Loop
-    }
+        }
 
-    fn enable_timeout(&mut self, stream: &mut TcpStream) {
-        stream.set_write_timeout_ms(Some(6000)).unwrap();
+        let _ = writer.shutdown().await;
+        println!("Connection closed");
     }
 
-    fn disable_timeout(&mut self, stream: &mut TcpStream) {
-        stream.set_write_timeout_ms(None).unwrap();
+    /// Sends a response, a client that doesn't read it in time is treated as gone
+    async fn write(writer: &mut WriteHalf<TcpStream>, response: &str) -> Result<(), Error> {
+        tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(response.as_bytes())).await??;
+
+        Ok(())
     }
 
-    fn send_subsystem_changed(&mut self, stream: &mut TcpStream) {
-        self.subsystems_changed.sort();
-        self.subsystems_changed.dedup();
-        let subsystems: Vec<String> = self.subsystems_changed.iter().map(|e| e.to_string().to_lowercase()).collect();
-        stream.write_all(format!("changed: {}\nOK\n", subsystems.join(", ")).as_bytes()).unwrap();
-        self.subsystems_changed.clear();
-        self.idle = false;
-        assert!(self.subsystems_changed.is_empty());
-        assert!(!self.idle);
-        self.enable_timeout(stream);
-        println!("<- OK (Subsystems changed)");
+    async fn handle_subsystem_events(&mut self, writer: &mut WriteHalf<TcpStream>, events: Vec<SubsystemEvent>) -> Result<(), Error> {
+        self.subsystems_changed.extend(events);
+        if self.idle.is_some() {
+            self.send_subsystem_changed(writer, false).await?;
+        }
+
+        Ok(())
     }
 
-    fn get_command_list(&self, stream: &mut TcpStream) -> Vec<String> {
-        let mut command_list = vec![];
-        if let Some(mut command) = MpdRequestHandler::get_cmd(stream) {
-            if command == "command_list_start" {
-                while command != "command_list_end" {
-                    if let Some(command) = MpdRequestHandler::get_cmd(stream) {
-                        if command != "command_list_end" {
-                            command_list.push(command);
-                        }
-                    }
-                }
-            } else {
-                command_list.push(command);
+    async fn handle_line(&mut self, writer: &mut WriteHalf<TcpStream>, line: String) -> Result<(), Error> {
+        if self.idle.is_some() {
+            if line == "noidle" {
+                println!("-> {:?}", line);
+                return self.send_subsystem_changed(writer, true).await;
             }
+
+            // Only noidle is allowed while idling
+            return Err(anyhow!("Unexpected command while idle: {}", line));
         }
 
-        command_list
-    }
+        if let Some(mut command_list) = self.command_list.take() {
+            if line == "command_list_end" {
+                return self.run_commands(writer, command_list, self.command_list_ok).await;
+            }
+            command_list.push(line);
+            self.command_list = Some(command_list);
 
-    fn get_cmd(stream: &mut TcpStream) -> Option<String>{
-        let mut response = String::new();
-        let mut buf_reader = BufReader::new(stream);
-        buf_reader.read_line(&mut response).expect("could not read");
+            return Ok(());
+        }
 
-        Some(response.trim().to_owned())
-    }
+        let command_name = line.split_whitespace().next().unwrap_or("");
+        match command_name {
+            "command_list_begin" | "command_list_ok_begin" => {
+                self.command_list = Some(vec![]);
+                self.command_list_ok = command_name == "command_list_ok_begin";
+                Ok(())
+            }
+            "idle" => {
+                println!("-> {:?}", line);
+                let subsystems = match Self::parse_idle_subsystems(&line) {
+                    Ok(subsystems) => subsystems,
+                    Err(e) => {
+                        let error = format!("{}\n", e.to_ack(0, "idle"));
+                        Self::write(writer, &error).await?;
+                        return Ok(());
+                    }
+                };
 
-    async fn run_commands(&self, stream: &mut TcpStream, command_list: Vec<String>) {
-        let mut output = vec![];
-        for command in command_list {
-            println!("-> {:?}", command);
-            if let Ok(result) = self.execute_command(command).await {
-                output.extend(result);
+                self.idle = Some(subsystems);
+                self.send_subsystem_changed(writer, false).await
             }
-            if self.has_error(&output) {
-                break;
+            // noidle without a preceding idle is ignored
+            "noidle" => Ok(()),
+            "close" => {
+                println!("-> {:?}", line);
+                self.closed = true;
+                Ok(())
             }
+            _ => self.run_commands(writer, vec![line], false).await,
         }
-        if self.has_error(&output) {
-            println!("<- {}", output.last().unwrap());
-        } else {
-            output.push("OK\n".to_owned());
-            println!("<- OK");
+    }
+
+    fn parse_idle_subsystems(line: &str) -> Result<Vec<SubsystemEvent>, MpdError> {
+        let request = Request::from_str(line)?;
+
+        request.args.iter().map(SubsystemEvent::from_str).collect()
+    }
+
+    /// Sends the changed subsystems the client is idling on. Nothing is sent unless
+    /// something changed or `force` is set, which is used to answer noidle.
+    async fn send_subsystem_changed(&mut self, writer: &mut WriteHalf<TcpStream>, force: bool) -> Result<(), Error> {
+        let filter = self.idle.take().unwrap_or_default();
+        let (changed, pending): (BTreeSet<SubsystemEvent>, BTreeSet<SubsystemEvent>) = std::mem::take(&mut self.subsystems_changed)
+            .into_iter()
+            .partition(|event| filter.is_empty() || filter.contains(event));
+        self.subsystems_changed = pending;
+
+        if changed.is_empty() && !force {
+            self.idle = Some(filter);
+            return Ok(());
         }
 
-        stream.write_all(output.join("\n").as_bytes()).unwrap();
+        let mut output: Vec<String> = changed.iter().map(|e| format!("changed: {}", e)).collect();
+        output.push("OK\n".to_owned());
+        Self::write(writer, &output.join("\n")).await?;
+        println!("<- OK (Subsystems changed)");
+
+        Ok(())
     }
 
-    fn has_error(&self, output: &[String]) -> bool {
-        for s in output {
-            if s.starts_with("ACK") {
-                return true;
+    /// Runs the commands in order and stops at the first one that fails. With `list_ok`
+    /// every successful command is acknowledged with its own `list_OK`.
+    async fn run_commands(&mut self, writer: &mut WriteHalf<TcpStream>, command_list: Vec<String>, list_ok: bool) -> Result<(), Error> {
+        let mut output = vec![];
+        for (list_index, command) in command_list.iter().enumerate() {
+            println!("-> {:?}", command);
+            match self.execute_command(command).await {
+                Ok(result) => {
+                    output.extend(self.filter_tags(result));
+                    if list_ok {
+                        output.push("list_OK".to_owned());
+                    }
+                }
+                Err(e) => {
+                    let command_name = command.split_whitespace().next().unwrap_or("");
+                    let ack = e.to_ack(list_index, command_name);
+                    println!("<- {}", ack);
+                    output.push(format!("{}\n", ack));
+                    Self::write(writer, &output.join("\n")).await?;
+
+                    return Ok(());
+                }
             }
         }
+        output.push("OK\n".to_owned());
+        println!("<- OK");
 
-        false
+        Self::write(writer, &output.join("\n")).await?;
+
+        Ok(())
     }
 
-    async fn execute_command(&self, command: String) -> Result<Vec<String>, Error> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new("\\s+\"?([^\"]*)\"?").unwrap();
+    async fn execute_command(&mut self, command: &str) -> Result<Vec<String>, MpdError> {
+        let request = Request::from_str(command)?;
+
+        if request.command == "tagtypes" {
+            return self.handle_tag_types(request.args);
+        }
+        // Single ones are handled as the line comes in, so these are part of a command list
+        if CONNECTION_COMMANDS.contains(&request.command.as_str()) {
+            return Err(MpdError::Arg(format!("{} not allowed in command list", request.command)));
         }
 
-        let command_name = command
-            .split_whitespace()
-            .next()
-            .unwrap_or("");
         for mpd_command in COMMANDS.iter() {
-            if mpd_command.get_type().contains(&command_name) {
-                let args: Option<regex::Captures<'_>> = RE.captures(&command);
+            if mpd_command.get_type().contains(&request.command.as_str()) {
+                let args = request.args;
                 let client = Arc::clone(&self.client);
 
-                return match mpd_command.handle(client, args).await {
-                    Ok(cmd) => Ok(cmd),
-                    Err(e) => Err(e)
-                };
+                return mpd_command.handle(client, args).await;
             }
         }
 
-        let mut output = vec![];
-        if command == "commands" {
-            for mpd_command in COMMANDS.iter() {
-                for t in mpd_command.get_type() {
-                    output.push(format!("command: {}", t));
+        Err(MpdError::Unknown("unknown command".to_owned()))
+    }
+
+    fn handle_tag_types(&mut self, args: Arguments) -> Result<Vec<String>, MpdError> {
+        match args.get(0) {
+            None => {
+                return Ok(self.tag_types.iter().map(|tag| format!("tagtype: {}", tag)).collect());
+            }
+            Some("all") => self.tag_types = TAG_TYPES.to_vec(),
+            Some("clear") => self.tag_types.clear(),
+            Some(action @ "enable") | Some(action @ "disable") => {
+                let mut tags = vec![];
+                for name in args.iter().skip(1) {
+                    match TAG_TYPES.iter().find(|tag| tag.eq_ignore_ascii_case(name)) {
+                        Some(tag) => tags.push(*tag),
+                        None => return Err(MpdError::Arg(format!("Unknown tag type: {}", name))),
+                    }
+                }
+                if tags.is_empty() {
+                    return Err(MpdError::Arg("Not enough arguments".to_owned()));
+                }
+
+                self.tag_types.retain(|tag| !tags.contains(tag));
+                if action == "enable" {
+                    self.tag_types.extend(tags);
+                    self.tag_types.sort_by_key(|tag| TAG_TYPES.iter().position(|t| t == tag));
                 }
             }
+            Some(action) => return Err(MpdError::Arg(format!("Unknown sub command: {}", action))),
         }
 
-        Ok(output)
+        Ok(vec![])
     }
-}
\ No newline at end of file
+
+    /// Drops the tags this connection disabled from the songs in a command response. Songs
+    /// start with their `file` line, so tag values listed on their own (`list`) stay.
+    fn filter_tags(&self, output: Vec<String>) -> Vec<String> {
+        let mut in_song = false;
+        output
+            .into_iter()
+            .filter(|line| {
+                let key = line.split(": ").next().unwrap_or("");
+                match key {
+                    "file" => in_song = true,
+                    "directory" | "playlist" => in_song = false,
+                    _ => {}
+                }
+                if !in_song {
+                    return true;
+                }
+
+                match TAG_TYPES.iter().find(|tag| tag.eq_ignore_ascii_case(key)) {
+                    Some(tag) => self.tag_types.contains(tag),
+                    None => true,
+                }
+            })
+            .collect()
+    }
+}
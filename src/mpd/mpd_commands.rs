@@ -1,18 +1,23 @@
-use rspotify::model::playlist::SimplifiedPlaylist;
-use rspotify::model::artist::SimplifiedArtist;
 use async_trait::async_trait;
-use anyhow::{Error, Result};
+use crate::mpd::error::MpdError;
+use crate::mpd::{COMMANDS, CONNECTION_COMMANDS};
 use std::sync::Arc;
 use crate::track::Track;
 use std::str::FromStr;
 use crate::respot::PlayerEvent;
+use crate::queue::SingleMode;
 use crate::mpd::{Client, SubsystemEvent};
-use regex::Captures;
+use crate::mpd::request::Arguments;
+use crate::mpd::filter::{Filter, Tag, Condition, Operator};
+use crate::mpd::library::{directory_songs, is_directory, list_directory, walk_directory, LibraryEntry};
+use crate::catalog::{NotFound, Playlist};
+use std::time::Duration;
+use std::ops::Range;
 
 #[async_trait]
 pub trait MpdCommand {
     fn get_type(&self) -> Vec<&str>;
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error>;
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError>;
 }
 
 pub struct StatusCommand;
@@ -23,26 +28,37 @@ impl MpdCommand for StatusCommand {
         vec!["status"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
-        output.push("repeat: 0");
-        output.push("random: 0");
-        output.push("single: 0");
-        output.push("consume: 0");
-        output.push("playlist: 1");
         output.push("mixrampdb: 0.00000");
 
         let mut output_strings: Vec<String> = output.iter().map(|x| (*x).to_string()).collect::<Vec<String>>();
+        output_strings.push(format!("repeat: {}", client.queue.get_repeat() as u8));
+        output_strings.push(format!("random: {}", client.queue.get_random() as u8));
+        output_strings.push(format!("single: {}", client.queue.get_single()));
+        output_strings.push(format!("consume: {}", client.queue.get_consume() as u8));
+        output_strings.push(format!("playlist: {}", client.queue.get_version()));
         output_strings.push(format!("volume: {}", client.queue.get_volume()));
         let status = client.queue.get_status();
         let playlist_length = client.queue.len();
         output_strings.push(format!("playlistlength: {}", playlist_length));
         output_strings.push(format!("state: {}", status.to_string()));
-        if status == PlayerEvent::Playing || status == PlayerEvent::Paused {
-            if let Some(songid) = client.queue.get_current_index() {
-                output_strings.push(format!("song: {}", songid));
-                output_strings.push(format!("songid: {}", songid));
+        if let Some(song) = client.queue.get_current_index() {
+            if let Some(entry) = client.queue.get_entry(song) {
+                output_strings.push(format!("song: {}", song));
+                output_strings.push(format!("songid: {}", entry.id));
+            }
+        }
+        // The next song is picked when the current one ends in random mode
+        if !client.queue.get_random() {
+            if let Some(next_song) = client.queue.next_index() {
+                if let Some(entry) = client.queue.get_entry(next_song) {
+                    output_strings.push(format!("nextsong: {}", next_song));
+                    output_strings.push(format!("nextsongid: {}", entry.id));
+                }
             }
+        }
+        if status == PlayerEvent::Playing || status == PlayerEvent::Paused {
             let elapsed = client.queue.get_current_elapsed_time();
             let duration = client.queue.get_duration();
             output_strings.push(format!("time: {}:{}", elapsed.as_secs(), duration));
@@ -64,7 +80,7 @@ impl MpdCommand for StatsCommand {
         vec!["stats"]
     }
 
-    async fn handle(&self, _: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
         output.push("uptime: 0");
         output.push("playtime: 0");
@@ -81,21 +97,13 @@ impl MpdCommand for ListPlaylistsCommand {
         vec!["listplaylists"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
-        let playlists_result = client.spotify.current_user_playlists(None, None).await;
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         let mut string_builder = vec![];
 
-        match playlists_result {
-            Ok(playlists) => {
-                for playlist in playlists.items {
-                    string_builder.push(format!("playlist: {}", playlist.name));
-                    // We don't know the time :(
-                    string_builder.push("Last-Modified: 1970-01-01T00:00:00Z".to_owned());
-                }
-            }
-            Err(e) => {
-                println!("error fetching playlists: {:?}", e)
-            }
+        for playlist in client.catalog.user_playlists().await.map_err(MpdError::system)? {
+            string_builder.push(format!("playlist: {}", playlist.name));
+            // We don't know the time :(
+            string_builder.push("Last-Modified: 1970-01-01T00:00:00Z".to_owned());
         }
 
         Ok(string_builder)
@@ -110,84 +118,280 @@ impl MpdCommand for ListPlaylistInfoCommand {
         vec!["listplaylistinfo"]
     }
 
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
-        let playlist_name = &args.unwrap()[1];
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        let tracks = client.catalog.playlist_tracks(&playlist).await.map_err(MpdError::system)?;
 
-        let simplified_playlist = self.get_playlist_by_name(Arc::clone(&client), playlist_name).await;
-        let mut string_builder = vec![];
-        match simplified_playlist {
-            Some(playlist) => {
-                let playlist_tracks_result = client.spotify.user_playlist_tracks(
-                    &self.get_current_user_id(&client).await?,
-                    &playlist.id,
-                    None,
-                    None,
-                    None,
-                    None,
-                ).await;
-                if let Ok(playlist_tracks) = playlist_tracks_result {
-                    for playlist_track in playlist_tracks.items
-                    {
-                        if let Some(track) = playlist_track.track {
-                            // We don't support local tracks
-                            if track.is_local {
-                                break;
-                            }
-                            string_builder.push(format!("file: {}", track.id.unwrap()));
-                            string_builder.push(format!("Last-Modified: {}", track.album.release_date.unwrap()));
-                            string_builder.push(format!("Artist: {}", self.get_artists(track.artists)));
-                            string_builder.push(format!("AlbumArtist: {}", self.get_artists(track.album.artists)));
-                            string_builder.push(format!("Title: {}", track.name));
-                            string_builder.push(format!("Album: {}", track.album.name));
-                            let seconds = track.duration_ms / 1000;
-                            string_builder.push(format!("Time: {}", seconds));
-                            string_builder.push(format!("duration: {}", seconds));
-                        }
-                    }
-                }
-            }
-            _ => {
-                string_builder.push("ACK".to_owned());
-            }
+        Ok(tracks.iter().flat_map(Track::to_mpd_format).collect())
+    }
+}
+
+pub struct ListPlaylistCommand;
+
+#[async_trait]
+impl MpdCommand for ListPlaylistCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["listplaylist"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        let tracks = client.catalog.playlist_tracks(&playlist).await.map_err(MpdError::system)?;
+
+        Ok(tracks
+            .iter()
+            .filter_map(|track| track.id.as_ref())
+            .map(|id| format!("file: {}", id))
+            .collect())
+    }
+}
+
+pub struct LoadCommand;
+
+#[async_trait]
+impl MpdCommand for LoadCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["load"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        let tracks = client.catalog.playlist_tracks(&playlist).await.map_err(MpdError::system)?;
+        let range = match args.get(1) {
+            Some(range) => parse_range(range, tracks.len())?,
+            None => 0..tracks.len(),
+        };
+
+        if range.start > range.end || range.end > tracks.len() {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
         }
 
-        Ok(string_builder)
+        for track in &tracks[range] {
+            client.queue.append(track);
+        }
+
+        Ok(vec![])
     }
 }
 
-impl ListPlaylistInfoCommand {
-    async fn get_playlist_by_name(&self, client: Arc<Client>, name: &str) -> Option<SimplifiedPlaylist> {
-        let playlists_result = client.spotify.current_user_playlists(None, None).await;
-        match playlists_result {
-            Ok(playlists) => {
-                for playlist in playlists.items {
-                    if playlist.name == name {
-                        return Some(playlist);
-                    }
-                }
-            }
-            Err(_) => {
-                println!("Unable to get playlist by name")
-            }
+pub struct PlaylistAddCommand;
+
+#[async_trait]
+impl MpdCommand for PlaylistAddCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["playlistadd"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let name = args.required(0)?;
+        let track_id = args.required(1)?;
+
+        if !find_track(&client, track_id).await?.is_track() {
+            return Err(MpdError::Arg("Podcast episodes can't be added to playlists".to_owned()));
         }
 
+        // Like MPD, adding to a playlist that doesn't exist yet creates it
+        let playlist = match find_playlist(&client, name).await {
+            Ok(playlist) => playlist,
+            Err(MpdError::NoExist(_)) => create_playlist(&client, name).await?,
+            Err(e) => return Err(e),
+        };
+
+        client.catalog.add_playlist_tracks(&playlist, &[track_id.to_owned()]).await
+            .map_err(MpdError::system)?;
+        client.notify(SubsystemEvent::StoragePlaylist);
+
+        Ok(vec![])
+    }
+}
+
+pub struct PlaylistClearCommand;
+
+#[async_trait]
+impl MpdCommand for PlaylistClearCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["playlistclear"]
+    }
 
-        None
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        // A single request replacing everything with nothing also drops the items we can't play
+        replace_playlist_tracks(&client, &playlist, &[]).await?;
+
+        Ok(vec![])
+    }
+}
+
+pub struct PlaylistDeleteCommand;
+
+#[async_trait]
+impl MpdCommand for PlaylistDeleteCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["playlistdelete"]
     }
 
-    async fn get_current_user_id(&self, client: &Arc<Client>) -> Result<String, Error> {
-        let current_user_result = client.spotify.current_user().await;
-        match current_user_result {
-            Ok(current_user) => Ok(current_user.id),
-            Err(e) => Err(Error::from(e.compat()))
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        let position = parse_number(args.required(1)?)?;
+
+        if position >= playlist_track_ids(&client, &playlist).await?.len() {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+        client.catalog.remove_playlist_track(&playlist, position).await
+            .map_err(MpdError::system)?;
+        client.notify(SubsystemEvent::StoragePlaylist);
+
+        Ok(vec![])
+    }
+}
+
+pub struct PlaylistMoveCommand;
+
+#[async_trait]
+impl MpdCommand for PlaylistMoveCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["playlistmove"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        let from = parse_number(args.required(1)?)?;
+        let to = parse_number(args.required(2)?)?;
+        let len = playlist_track_ids(&client, &playlist).await?.len();
+
+        if from >= len || to >= len {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+
+        client.catalog.move_playlist_track(&playlist, from, to).await
+            .map_err(MpdError::system)?;
+        client.notify(SubsystemEvent::StoragePlaylist);
+
+        Ok(vec![])
+    }
+}
+
+pub struct RenameCommand;
+
+#[async_trait]
+impl MpdCommand for RenameCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["rename"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+        let new_name = args.required(1)?;
+
+        if find_playlist(&client, new_name).await.is_ok() {
+            return Err(MpdError::Exist("Playlist already exists".to_owned()));
         }
+
+        client.catalog.rename_playlist(&playlist, new_name).await
+            .map_err(MpdError::system)?;
+        client.notify(SubsystemEvent::StoragePlaylist);
+
+        Ok(vec![])
     }
+}
 
-    fn get_artists(&self, artists: Vec<SimplifiedArtist>) -> String {
-        let artists_vec: Vec<String> = artists.iter().map(|x| x.name.to_owned()).collect();
+pub struct RmCommand;
 
-        artists_vec.join(";")
+#[async_trait]
+impl MpdCommand for RmCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["rm"]
     }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let playlist = find_playlist(&client, args.required(0)?).await?;
+
+        client.catalog.remove_playlist(&playlist).await
+            .map_err(MpdError::system)?;
+        client.notify(SubsystemEvent::StoragePlaylist);
+
+        Ok(vec![])
+    }
+}
+
+pub struct SaveCommand;
+
+#[async_trait]
+impl MpdCommand for SaveCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["save"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let name = args.required(0)?;
+
+        if find_playlist(&client, name).await.is_ok() {
+            return Err(MpdError::Exist("Playlist already exists".to_owned()));
+        }
+
+        let (tracks, episodes): (Vec<Track>, Vec<Track>) = client.queue.queue
+            .read()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.track.clone())
+            .partition(Track::is_track);
+        if !episodes.is_empty() {
+            warn!("Leaving {} podcast episodes out of playlist {}, Spotify playlists only take tracks", episodes.len(), name);
+        }
+        let track_ids: Vec<String> = tracks.into_iter().filter_map(|track| track.id).collect();
+        let playlist = create_playlist(&client, name).await?;
+        replace_playlist_tracks(&client, &playlist, &track_ids).await?;
+
+        Ok(vec![])
+    }
+}
+
+/// Finds one of the user's playlists by the name MPD clients know it by
+async fn find_playlist(client: &Arc<Client>, name: &str) -> Result<Playlist, MpdError> {
+    client.catalog.user_playlists().await
+        .map_err(MpdError::system)?
+        .into_iter()
+        .find(|playlist| playlist.name == name)
+        .ok_or_else(|| MpdError::NoExist("No such playlist".to_owned()))
+}
+
+/// Looks up a track or podcast episode, only an id the catalog doesn't know is a missing song
+async fn find_track(client: &Arc<Client>, id: &str) -> Result<Track, MpdError> {
+    match client.catalog.track(id).await {
+        Ok(track) => Ok(track),
+        // Episodes are listed as songs too, but Spotify looks them up separately
+        Err(e) if e.is::<NotFound>() => client.catalog.episode(id).await.map_err(|episode_error| {
+            if episode_error.is::<NotFound>() {
+                MpdError::NoExist("No such song".to_owned())
+            } else {
+                MpdError::system(episode_error)
+            }
+        }),
+        Err(e) => Err(MpdError::system(e)),
+    }
+}
+
+async fn playlist_track_ids(client: &Arc<Client>, playlist: &Playlist) -> Result<Vec<String>, MpdError> {
+    let tracks = client.catalog.playlist_tracks(playlist).await
+        .map_err(MpdError::system)?;
+
+    Ok(tracks.into_iter().filter_map(|track| track.id).collect())
+}
+
+async fn create_playlist(client: &Arc<Client>, name: &str) -> Result<Playlist, MpdError> {
+    let playlist = client.catalog.create_playlist(name).await
+        .map_err(MpdError::system)?;
+    client.notify(SubsystemEvent::StoragePlaylist);
+
+    Ok(playlist)
+}
+
+async fn replace_playlist_tracks(client: &Arc<Client>, playlist: &Playlist, track_ids: &[String]) -> Result<(), MpdError> {
+    client.catalog.replace_playlist_tracks(playlist, track_ids).await
+        .map_err(MpdError::system)?;
+    client.notify(SubsystemEvent::StoragePlaylist);
+
+    Ok(())
 }
 
 pub struct AddCommand;
@@ -198,36 +402,114 @@ impl MpdCommand for AddCommand {
         vec!["add", "addid"]
     }
 
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
-        let track_id = &args.unwrap()[1];
-        let track_result = client.spotify.track(track_id).await;
-        match track_result {
-            Ok(full_track) => {
-                let track = Track::from(&full_track);
-                let song_id = client.queue.append(&track);
-                output.push(format!("Id: {}", song_id))
-            }
-            Err(e) => return Err(Error::from(e.compat()))
+        let uri = args.required(0)?;
+        let position = match args.get(1) {
+            Some(position) => Some(parse_position(&client, position)?),
+            None => None,
+        };
+
+        // Directories of the library add every song below them
+        let tracks = if is_directory(uri) {
+            directory_songs(&client, uri).await?
+        } else {
+            vec![find_track(&client, uri).await?]
+        };
+
+        for (offset, track) in tracks.iter().enumerate() {
+            let song_id = match position {
+                Some(position) => client.queue.insert(track, position + offset)
+                    .ok_or_else(|| MpdError::Arg("Bad song index".to_owned()))?,
+                None => client.queue.append(track),
+            };
+            output.push(format!("Id: {}", song_id));
         }
 
         Ok(output)
     }
 }
 
+/// Parses an absolute queue position, or one relative to the current song
+/// where `+0` is right after and `-0` is right before the current song.
+fn parse_position(client: &Arc<Client>, position: &str) -> Result<usize, MpdError> {
+    let position = position.trim();
+    if position.starts_with('+') || position.starts_with('-') {
+        let current = client.queue.get_current_index()
+            .ok_or_else(|| MpdError::Arg("No current song".to_owned()))?;
+        let offset = parse_number(&position[1..])?;
+
+        if position.starts_with('+') {
+            (current + 1).checked_add(offset).ok_or_else(|| MpdError::Arg("Bad song index".to_owned()))
+        } else {
+            current.checked_sub(offset).ok_or_else(|| MpdError::Arg("Bad song index".to_owned()))
+        }
+    } else {
+        parse_number(position)
+    }
+}
+
+pub struct DeleteCommand;
+
+#[async_trait]
+impl MpdCommand for DeleteCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["delete"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let range = parse_range(args.required(0)?, client.queue.len())?;
+
+        if !client.queue.remove_range(range) {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+
+        Ok(vec![])
+    }
+}
+
 pub struct PlayCommand;
 
 #[async_trait]
 impl MpdCommand for PlayCommand {
     fn get_type(&self) -> Vec<&str> {
-        vec!["play", "playid"]
+        vec!["play"]
     }
 
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
-        match args {
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        match args.get(0) {
             Some(arg) => {
-                let index = usize::from_str(&arg[1]).unwrap();
-                client.queue.play_id(index);
+                let index = parse_number(arg)?;
+                if !client.queue.play_index(index) {
+                    return Err(MpdError::Arg("Bad song index".to_owned()));
+                }
+            }
+            None => {
+                client.queue.play();
+            }
+        }
+
+        Ok(vec![])
+    }
+}
+
+pub struct PlayIdCommand;
+
+#[async_trait]
+impl MpdCommand for PlayIdCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["playid"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        match args.parse_optional::<usize>(0)? {
+            Some(song_id) => {
+                match client.queue.get_index_by_id(song_id) {
+                    Some(index) => {
+                        client.queue.play_index(index);
+                    }
+                    None => return Err(MpdError::NoExist("No such song".to_owned())),
+                }
             }
             None => {
                 client.queue.play();
@@ -246,13 +528,28 @@ impl MpdCommand for PauseCommand {
         vec!["pause"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         client.queue.toggle_playback();
 
         Ok(vec![])
     }
 }
 
+pub struct StopCommand;
+
+#[async_trait]
+impl MpdCommand for StopCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["stop"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
+        client.queue.stop_playback();
+
+        Ok(vec![])
+    }
+}
+
 pub struct NextCommand;
 
 #[async_trait]
@@ -261,7 +558,7 @@ impl MpdCommand for NextCommand {
         vec!["next"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         client.queue.next();
 
         Ok(vec![])
@@ -273,10 +570,10 @@ pub struct PrevCommand;
 #[async_trait]
 impl MpdCommand for PrevCommand {
     fn get_type(&self) -> Vec<&str> {
-        vec!["prev"]
+        vec!["previous", "prev"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         client.queue.previous();
 
         Ok(vec![])
@@ -291,7 +588,7 @@ impl MpdCommand for ClearCommand {
         vec!["clear"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         client.queue.clear();
 
         Ok(vec![])
@@ -303,14 +600,80 @@ pub struct PlaylistInfoCommand;
 #[async_trait]
 impl MpdCommand for PlaylistInfoCommand {
     fn get_type(&self) -> Vec<&str> {
-        vec!["playlistinfo", "plchanges"]
+        vec!["playlistinfo"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
+        let mut output = vec![];
+        let queue = client.queue.queue.read().unwrap();
+        for (pos, entry) in (*queue).iter().enumerate() {
+            output.extend(entry.track.to_mpd_queue_format(pos, entry.id));
+        }
+
+        Ok(output)
+    }
+}
+
+pub struct PlChangesCommand;
+
+#[async_trait]
+impl MpdCommand for PlChangesCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["plchanges"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let mut output = vec![];
+        let version = args.parse::<u32>(0)?;
+        for (pos, entry) in client.queue.changes_since(version) {
+            output.extend(entry.track.to_mpd_queue_format(pos, entry.id));
+        }
+
+        Ok(output)
+    }
+}
+
+pub struct PlChangesPosIdCommand;
+
+#[async_trait]
+impl MpdCommand for PlChangesPosIdCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["plchangesposid"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let mut output = vec![];
+        let version = args.parse::<u32>(0)?;
+        for (pos, entry) in client.queue.changes_since(version) {
+            output.push(format!("cpos: {}", pos));
+            output.push(format!("Id: {}", entry.id));
+        }
+
+        Ok(output)
+    }
+}
+
+pub struct PlaylistIdCommand;
+
+#[async_trait]
+impl MpdCommand for PlaylistIdCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["playlistid"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
+        let song_id = args.parse_optional::<usize>(0)?;
+
         let queue = client.queue.queue.read().unwrap();
-        for (pos, track) in (*queue).clone().into_iter().enumerate() {
-            output.extend(track.to_mpd_format(pos));
+        for (pos, entry) in (*queue).iter().enumerate() {
+            if song_id.is_none() || song_id == Some(entry.id) {
+                output.extend(entry.track.to_mpd_queue_format(pos, entry.id));
+            }
+        }
+
+        if song_id.is_some() && output.is_empty() {
+            return Err(MpdError::NoExist("No such song".to_owned()));
         }
 
         Ok(output)
@@ -325,10 +688,12 @@ impl MpdCommand for CurrentSongCommand {
         vec!["currentsong"]
     }
 
-    async fn handle(&self, client: Arc<Client>, _: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
-        if let Some(current_track) = client.queue.get_current() {
-            output = current_track.to_mpd_format(0);
+        if let Some(pos) = client.queue.get_current_index() {
+            if let Some(entry) = client.queue.get_entry(pos) {
+                output = entry.track.to_mpd_queue_format(pos, entry.id);
+            }
         }
 
         Ok(output)
@@ -343,12 +708,13 @@ impl MpdCommand for SetVolCommand {
         vec!["setvol"]
     }
 
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
-        let volume_level = &args.unwrap()[1];
-
-        client.queue.set_volume(volume_level.parse::<u16>().unwrap());
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let volume_level = args.parse::<u16>(0)?;
+        if volume_level > 100 {
+            return Err(MpdError::Arg("Invalid volume value".to_owned()));
+        }
 
-        client.event_bus.lock().unwrap().broadcast(SubsystemEvent::Mixer);
+        client.queue.set_volume(volume_level);
 
         Ok(vec![])
     }
@@ -362,13 +728,11 @@ impl MpdCommand for VolumeCommand {
         vec!["volume"]
     }
 
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
-        let volume_level_str = &args.unwrap()[1];
-        let volume_level = volume_level_str.parse::<i16>().unwrap();
-
-        client.queue.set_volume(client.queue.get_volume().wrapping_add(volume_level as u16));
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let volume_change = args.parse::<i32>(0)?;
+        let volume_level = (client.queue.get_volume() as i32).saturating_add(volume_change).clamp(0, 100);
 
-        client.event_bus.lock().unwrap().broadcast(SubsystemEvent::Mixer);
+        client.queue.set_volume(volume_level as u16);
 
         Ok(vec![])
     }
@@ -382,17 +746,325 @@ impl MpdCommand for DeleteIdCommand {
         vec!["deleteid"]
     }
 
-    async fn handle(&self, client: Arc<Client>, args: Option<regex::Captures<'_>>) -> Result<Vec<String>, Error> {
-        let song_id_arg = &args.unwrap()[1];
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let song_id = args.parse::<usize>(0)?;
+        match client.queue.get_index_by_id(song_id) {
+            Some(index) => client.queue.remove(index),
+            None => return Err(MpdError::NoExist("No such song".to_owned())),
+        }
 
-        if let Ok(song_id) = usize::from_str(song_id_arg) {
-            client.queue.remove(song_id);
+        Ok(vec![])
+    }
+}
+
+pub struct SeekCommand;
+
+#[async_trait]
+impl MpdCommand for SeekCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["seek"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let index = args.parse::<usize>(0)?;
+        let position = parse_seconds(&args, 1)?;
+
+        seek_index(&client, index, position)?;
+
+        Ok(vec![])
+    }
+}
+
+pub struct SeekIdCommand;
+
+#[async_trait]
+impl MpdCommand for SeekIdCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["seekid"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let song_id = args.parse::<usize>(0)?;
+        let position = parse_seconds(&args, 1)?;
+
+        match client.queue.get_index_by_id(song_id) {
+            Some(index) => seek_index(&client, index, position)?,
+            None => return Err(MpdError::NoExist("No such song".to_owned())),
         }
 
         Ok(vec![])
     }
 }
 
+fn seek_index(client: &Arc<Client>, index: usize, position: f64) -> Result<(), MpdError> {
+    let position = Duration::from_secs_f64(position.max(0.0));
+    // A stopped current song is started at the position like any other
+    if client.queue.get_current_index() == Some(index) && client.queue.get_status() != PlayerEvent::Stopped {
+        seek_current(client.queue.seek(position))
+    } else if client.queue.play_index_at(index, position) {
+        Ok(())
+    } else {
+        Err(MpdError::Arg("Bad song index".to_owned()))
+    }
+}
+
+/// Turns the result of seeking into the error MPD answers with when nothing is playing
+fn seek_current(seeked: bool) -> Result<(), MpdError> {
+    if seeked {
+        Ok(())
+    } else {
+        Err(MpdError::PlayerSync("Not playing".to_owned()))
+    }
+}
+
+pub struct SeekCurCommand;
+
+#[async_trait]
+impl MpdCommand for SeekCurCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["seekcur"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let time_arg = args.required(0)?;
+        let position = parse_seconds(&args, 0)?;
+
+        // A leading sign means the position is relative to the current playing position
+        let seeked = if time_arg.starts_with('+') || time_arg.starts_with('-') {
+            client.queue.seek_relative(position)
+        } else {
+            client.queue.seek(Duration::from_secs_f64(position.max(0.0)))
+        };
+        seek_current(seeked)?;
+
+        Ok(vec![])
+    }
+}
+
+pub struct RepeatCommand;
+
+#[async_trait]
+impl MpdCommand for RepeatCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["repeat"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        client.queue.set_repeat(parse_state(args.required(0)?)?);
+
+        Ok(vec![])
+    }
+}
+
+pub struct RandomCommand;
+
+#[async_trait]
+impl MpdCommand for RandomCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["random"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        client.queue.set_random(parse_state(args.required(0)?)?);
+
+        Ok(vec![])
+    }
+}
+
+pub struct SingleCommand;
+
+#[async_trait]
+impl MpdCommand for SingleCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["single"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let single = match args.required(0)? {
+            "oneshot" => SingleMode::Oneshot,
+            state => {
+                if parse_state(state)? {
+                    SingleMode::On
+                } else {
+                    SingleMode::Off
+                }
+            }
+        };
+        client.queue.set_single(single);
+
+        Ok(vec![])
+    }
+}
+
+pub struct ConsumeCommand;
+
+#[async_trait]
+impl MpdCommand for ConsumeCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["consume"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        client.queue.set_consume(parse_state(args.required(0)?)?);
+
+        Ok(vec![])
+    }
+}
+
+pub struct MoveCommand;
+
+#[async_trait]
+impl MpdCommand for MoveCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["move"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let range = parse_range(args.required(0)?, client.queue.len())?;
+        let to = args.parse::<usize>(1)?;
+
+        if !client.queue.move_range(range, to) {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+
+        Ok(vec![])
+    }
+}
+
+pub struct MoveIdCommand;
+
+#[async_trait]
+impl MpdCommand for MoveIdCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["moveid"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let song_id = args.parse::<usize>(0)?;
+        let to = args.parse::<usize>(1)?;
+
+        let index = client.queue.get_index_by_id(song_id).ok_or_else(|| MpdError::NoExist("No such song".to_owned()))?;
+        if !client.queue.move_range(index..index + 1, to) {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+
+        Ok(vec![])
+    }
+}
+
+pub struct SwapCommand;
+
+#[async_trait]
+impl MpdCommand for SwapCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["swap"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let first = args.parse::<usize>(0)?;
+        let second = args.parse::<usize>(1)?;
+
+        if !client.queue.swap(first, second) {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+
+        Ok(vec![])
+    }
+}
+
+pub struct SwapIdCommand;
+
+#[async_trait]
+impl MpdCommand for SwapIdCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["swapid"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let first_id = args.parse::<usize>(0)?;
+        let second_id = args.parse::<usize>(1)?;
+
+        let first = client.queue.get_index_by_id(first_id).ok_or_else(|| MpdError::NoExist("No such song".to_owned()))?;
+        let second = client.queue.get_index_by_id(second_id).ok_or_else(|| MpdError::NoExist("No such song".to_owned()))?;
+        // The queue may have changed since the ids were looked up
+        if !client.queue.swap(first, second) {
+            return Err(MpdError::NoExist("No such song".to_owned()));
+        }
+
+        Ok(vec![])
+    }
+}
+
+pub struct ShuffleCommand;
+
+#[async_trait]
+impl MpdCommand for ShuffleCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["shuffle"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let len = client.queue.len();
+        let range = match args.get(0) {
+            Some(arg) => parse_range(arg, len)?,
+            None => 0..len,
+        };
+
+        if !client.queue.shuffle(range) {
+            return Err(MpdError::Arg("Bad song index".to_owned()));
+        }
+
+        Ok(vec![])
+    }
+}
+
+/// Parses a `START:END` range where `END` is optional, or a single position
+fn parse_range(range: &str, len: usize) -> Result<Range<usize>, MpdError> {
+    let range = range.trim();
+    match range.find(':') {
+        Some(separator) => {
+            let start = parse_number(&range[..separator])?;
+            let end = match &range[separator + 1..] {
+                "" => len,
+                end => parse_number(end)?,
+            };
+
+            Ok(start..end)
+        }
+        None => {
+            let pos = parse_number(range)?;
+            let end = pos.checked_add(1).ok_or_else(|| MpdError::Arg("Bad song index".to_owned()))?;
+
+            Ok(pos..end)
+        }
+    }
+}
+
+/// The furthest the player can seek, it takes positions in milliseconds as `u32`
+const MAX_SEEK_SECONDS: f64 = u32::MAX as f64 / 1000.0;
+
+fn parse_seconds(args: &Arguments, index: usize) -> Result<f64, MpdError> {
+    let seconds = args.parse::<f64>(index)?;
+    if !seconds.is_finite() {
+        return Err(MpdError::Arg(format!("Invalid time: {}", seconds)));
+    }
+
+    // Seeking past the end of a track ends up at its end anyway
+    Ok(seconds.clamp(-MAX_SEEK_SECONDS, MAX_SEEK_SECONDS))
+}
+
+fn parse_number(number: &str) -> Result<usize, MpdError> {
+    usize::from_str(number).map_err(|_| MpdError::Arg(format!("Integer expected: {}", number)))
+}
+
+fn parse_state(state: &str) -> Result<bool, MpdError> {
+    match state.trim() {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        state => Err(MpdError::Arg(format!("Boolean (0/1) expected: {}", state))),
+    }
+}
+
 pub struct UrlHandlersCommand;
 
 #[async_trait]
@@ -401,7 +1073,7 @@ impl MpdCommand for UrlHandlersCommand {
         vec!["urlhandlers"]
     }
 
-    async fn handle(&self, _: Arc<Client>, _: Option<Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         Ok(vec!["handler: spotify:".to_owned()])
     }
 }
@@ -414,7 +1086,7 @@ impl MpdCommand for OutputsCommand {
         vec!["outputs"]
     }
 
-    async fn handle(&self, _: Arc<Client>, _: Option<Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
 
         output.push("outputsoutputid: 0");
@@ -433,10 +1105,10 @@ pub struct DecodersCommand;
 #[async_trait]
 impl MpdCommand for DecodersCommand {
     fn get_type(&self) -> Vec<&str> {
-        vec!["outputs"]
+        vec!["decoders"]
     }
 
-    async fn handle(&self, _: Arc<Client>, _: Option<Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
         let mut output = vec![];
 
         output.push("plugin: mad");
@@ -450,45 +1122,320 @@ impl MpdCommand for DecodersCommand {
     }
 }
 
-pub struct TagTypesCommand;
+pub struct SearchCommand;
 
 #[async_trait]
-impl MpdCommand for TagTypesCommand {
+impl MpdCommand for SearchCommand {
     fn get_type(&self) -> Vec<&str> {
-        vec!["tagtypes"]
+        vec!["search"]
     }
 
-    async fn handle(&self, _: Arc<Client>, _: Option<Captures<'_>>) -> Result<Vec<String>, Error> {
-        let mut output = vec![];
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let filter = Filter::parse(&args, false)?;
+        let tracks = find_tracks(&client, &filter, false).await?;
 
-        output.push("tagtype: Artist");
-        output.push("tagtype: ArtistSort");
-        output.push("tagtype: Album");
-        output.push("tagtype: AlbumSort");
-        output.push("tagtype: AlbumArtist");
-        output.push("tagtype: AlbumArtistSort");
-        output.push("tagtype: Title");
-        output.push("tagtype: Name");
-        output.push("tagtype: Genre");
-        output.push("tagtype: Date");
+        Ok(tracks.iter().flat_map(Track::to_mpd_format).collect())
+    }
+}
 
-        Ok(output.iter().map(|x| (*x).to_string()).collect::<Vec<String>>())
+pub struct FindCommand;
+
+#[async_trait]
+impl MpdCommand for FindCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["find"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let filter = Filter::parse(&args, true)?;
+        let tracks = find_tracks(&client, &filter, true).await?;
+
+        Ok(tracks.iter().flat_map(Track::to_mpd_format).collect())
+    }
+}
+
+pub struct SearchAddCommand;
+
+#[async_trait]
+impl MpdCommand for SearchAddCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["searchadd"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let filter = Filter::parse(&args, false)?;
+        for track in find_tracks(&client, &filter, false).await? {
+            client.queue.append(&track);
+        }
+
+        Ok(vec![])
+    }
+}
+
+pub struct FindAddCommand;
+
+#[async_trait]
+impl MpdCommand for FindAddCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["findadd"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let filter = Filter::parse(&args, true)?;
+        for track in find_tracks(&client, &filter, true).await? {
+            client.queue.append(&track);
+        }
+
+        Ok(vec![])
     }
 }
 
-pub struct IdleCommand;
+pub struct ListCommand;
 
 #[async_trait]
-impl MpdCommand for IdleCommand {
+impl MpdCommand for ListCommand {
     fn get_type(&self) -> Vec<&str> {
-        vec!["idle"]
+        vec!["list"]
     }
 
-    async fn handle(&self, _: Arc<Client>, _: Option<Captures<'_>>) -> Result<Vec<String>, Error> {
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let tag = Tag::from_str(args.required(0)?)?;
+        if tag == Tag::Any {
+            return Err(MpdError::Arg("Cannot list any".to_owned()));
+        }
+
+        let filter_args = args.skip(1);
+        let filter = match (tag, filter_args.get(0), filter_args.get(1)) {
+            // Legacy `list album ARTIST`
+            (Tag::Album, Some(artist), None) if !artist.starts_with('(') => Filter {
+                conditions: vec![Condition {
+                    tag: Tag::Artist,
+                    operator: Operator::Equals,
+                    value: artist.to_owned(),
+                    negated: false,
+                }],
+                ..Filter::default()
+            },
+            _ => Filter::parse(&filter_args, true)?,
+        };
+
+        let tracks = find_tracks(&client, &filter, true).await?;
+        let mut rows: Vec<(Vec<String>, String)> = tracks
+            .iter()
+            .flat_map(|track| {
+                let group_values: Vec<String> = filter.group
+                    .iter()
+                    .map(|group| group.values(track).into_iter().next().unwrap_or_default())
+                    .collect();
+                tag.values(track)
+                    .into_iter()
+                    .map(move |value| (group_values.clone(), value))
+            })
+            .collect();
+        rows.sort();
+        rows.dedup();
+
         let mut output = vec![];
+        let mut previous_group: Option<Vec<String>> = None;
+        for (group_values, value) in rows {
+            for (index, group_value) in group_values.iter().enumerate() {
+                let changed = previous_group
+                    .as_ref()
+                    .is_none_or(|previous| previous[..=index] != group_values[..=index]);
+                if changed {
+                    output.push(format!("{}: {}", filter.group[index].name(), group_value));
+                }
+            }
+            output.push(format!("{}: {}", tag.name(), value));
+            previous_group = Some(group_values);
+        }
+
+        Ok(output)
+    }
+}
 
-        output.push("changed: mixer");
+pub struct CountCommand;
 
-        Ok(output.iter().map(|x| (*x).to_string()).collect::<Vec<String>>())
+#[async_trait]
+impl MpdCommand for CountCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["count"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let filter = Filter::parse(&args, true)?;
+        let tracks = find_tracks(&client, &filter, true).await?;
+
+        let mut output = vec![];
+        match filter.group.first() {
+            Some(group) => {
+                let mut group_values: Vec<String> = tracks
+                    .iter()
+                    .flat_map(|track| group.values(track))
+                    .collect();
+                group_values.sort();
+                group_values.dedup();
+
+                for group_value in group_values {
+                    let grouped: Vec<&Track> = tracks
+                        .iter()
+                        .filter(|track| group.values(track).contains(&group_value))
+                        .collect();
+                    output.push(format!("{}: {}", group.name(), group_value));
+                    output.push(format!("songs: {}", grouped.len()));
+                    output.push(format!("playtime: {}", grouped.iter().map(|track| track.duration / 1000).sum::<u32>()));
+                }
+            }
+            None => {
+                output.push(format!("songs: {}", tracks.len()));
+                output.push(format!("playtime: {}", tracks.iter().map(|track| track.duration / 1000).sum::<u32>()));
+            }
+        }
+
+        Ok(output)
+    }
+}
+
+/// Looks up the tracks matching `filter` on Spotify. Filters without any searchable
+/// terms are matched against the user's liked songs since Spotify can't list its catalog.
+async fn find_tracks(client: &Arc<Client>, filter: &Filter, case_sensitive: bool) -> Result<Vec<Track>, MpdError> {
+    let mut tracks = vec![];
+    for file in filter.files() {
+        if let Ok(track) = client.catalog.track(file).await {
+            tracks.push(track);
+        }
+    }
+
+    let query = filter.to_spotify_query();
+    if !query.is_empty() {
+        tracks.extend(client.catalog.search_tracks(&query).await.map_err(MpdError::system)?);
+    } else if filter.files().is_empty() {
+        tracks.extend(client.catalog.saved_tracks().await.map_err(MpdError::system)?);
+    }
+
+    tracks.retain(|track| track.is_playable() && filter.matches(track, case_sensitive));
+
+    Ok(match &filter.window {
+        Some(window) => tracks
+            .into_iter()
+            .skip(window.start)
+            .take(window.end.saturating_sub(window.start))
+            .collect(),
+        None => tracks,
+    })
+}
+
+pub struct PingCommand;
+
+#[async_trait]
+impl MpdCommand for PingCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["ping"]
+    }
+
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
+        Ok(vec![])
+    }
+}
+
+pub struct CommandsCommand;
+
+#[async_trait]
+impl MpdCommand for CommandsCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["commands"]
+    }
+
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
+        let mut commands: Vec<&str> = COMMANDS
+            .iter()
+            .flat_map(|command| command.get_type())
+            .chain(CONNECTION_COMMANDS.iter().cloned())
+            .collect();
+        commands.sort();
+        commands.dedup();
+
+        Ok(commands.iter().map(|command| format!("command: {}", command)).collect())
+    }
+}
+
+pub struct NotCommandsCommand;
+
+#[async_trait]
+impl MpdCommand for NotCommandsCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["notcommands"]
+    }
+
+    // There is no password support yet, so every client may use every command
+    async fn handle(&self, _: Arc<Client>, _: Arguments) -> Result<Vec<String>, MpdError> {
+        Ok(vec![])
+    }
+}
+
+pub struct LsInfoCommand;
+
+#[async_trait]
+impl MpdCommand for LsInfoCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["lsinfo"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let path = args.get(0).unwrap_or("");
+        match list_directory(&client, path).await {
+            Ok(entries) => Ok(entries.iter().flat_map(LibraryEntry::to_mpd_format).collect()),
+            // Songs live at the root of their URI, so anything else might be a track id
+            Err(e) if path.contains('/') => Err(e),
+            Err(e) => match client.catalog.track(path).await {
+                Ok(track) => Ok(track.to_mpd_format()),
+                Err(_) => Err(e),
+            },
+        }
+    }
+}
+
+pub struct ListAllCommand;
+
+#[async_trait]
+impl MpdCommand for ListAllCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["listall"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let entries = walk_directory(&client, args.get(0).unwrap_or("")).await?;
+
+        Ok(entries.iter().map(LibraryEntry::to_mpd_uri).collect())
+    }
+}
+
+pub struct ListAllInfoCommand;
+
+#[async_trait]
+impl MpdCommand for ListAllInfoCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["listallinfo"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let entries = walk_directory(&client, args.get(0).unwrap_or("")).await?;
+
+        Ok(entries.iter().flat_map(LibraryEntry::to_mpd_format).collect())
+    }
+}
+
+pub struct ListFilesCommand;
+
+#[async_trait]
+impl MpdCommand for ListFilesCommand {
+    fn get_type(&self) -> Vec<&str> {
+        vec!["listfiles"]
+    }
+
+    async fn handle(&self, client: Arc<Client>, args: Arguments) -> Result<Vec<String>, MpdError> {
+        let entries = list_directory(&client, args.get(0).unwrap_or("")).await?;
+
+        Ok(entries.iter().map(LibraryEntry::to_mpd_file).collect())
     }
 }
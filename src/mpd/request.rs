@@ -0,0 +1,191 @@
+use crate::mpd::error::MpdError;
+use std::str::FromStr;
+
+/// A single MPD request line split into the command name and its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub command: String,
+    pub args: Arguments,
+}
+
+impl FromStr for Request {
+    type Err = MpdError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(line)?.into_iter();
+        let command = match tokens.next() {
+            Some(Token::Unquoted(command)) => command,
+            Some(Token::Quoted(_)) => return Err(MpdError::Unknown("Command name must not be quoted".to_owned())),
+            None => return Err(MpdError::Unknown("No command given".to_owned())),
+        };
+
+        Ok(Self {
+            command,
+            args: Arguments(tokens.map(Token::into_string).collect()),
+        })
+    }
+}
+
+/// The arguments of a request with quoting and escapes already resolved
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Arguments(Vec<String>);
+
+impl From<Vec<String>> for Arguments {
+    fn from(args: Vec<String>) -> Self {
+        Self(args)
+    }
+}
+
+impl Arguments {
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.0.get(index).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// The arguments following the first `count` ones
+    pub fn skip(&self, count: usize) -> Arguments {
+        Self(self.0.iter().skip(count).cloned().collect())
+    }
+
+    /// Returns the argument at `index` or an error if the client did not send it
+    pub fn required(&self, index: usize) -> Result<&str, MpdError> {
+        self.get(index).ok_or_else(|| MpdError::Arg("wrong number of arguments".to_owned()))
+    }
+
+    pub fn parse<T: FromStr>(&self, index: usize) -> Result<T, MpdError> {
+        let arg = self.required(index)?;
+        T::from_str(arg).map_err(|_| MpdError::Arg(format!("Invalid argument: {}", arg)))
+    }
+
+    pub fn parse_optional<T: FromStr>(&self, index: usize) -> Result<Option<T>, MpdError> {
+        match self.get(index) {
+            Some(_) => self.parse(index).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+enum Token {
+    Unquoted(String),
+    Quoted(String),
+}
+
+impl Token {
+    fn into_string(self) -> String {
+        match self {
+            Token::Unquoted(token) | Token::Quoted(token) => token,
+        }
+    }
+}
+
+/// Splits a request line on whitespace, where a token starting with a double quote runs
+/// until the next unescaped double quote and a backslash escapes the character after it.
+fn tokenize(line: &str) -> Result<Vec<Token>, MpdError> {
+    let mut tokens = vec![];
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => token.push(escaped),
+                        None => return Err(MpdError::Arg("Missing closing '\"'".to_owned())),
+                    },
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => return Err(MpdError::Arg("Missing closing '\"'".to_owned())),
+                }
+            }
+
+            // A closing quote has to end the token
+            if let Some(&next) = chars.peek() {
+                if !next.is_whitespace() {
+                    return Err(MpdError::Arg("Space expected after closing '\"'".to_owned()));
+                }
+            }
+            tokens.push(Token::Quoted(token));
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Unquoted(token));
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Request, MpdError> {
+        line.parse()
+    }
+
+    fn args(args: &[&str]) -> Arguments {
+        Arguments(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        let request = parse("  add\tspotify:track:1   2 ").unwrap();
+
+        assert_eq!(request.command, "add");
+        assert_eq!(request.args, args(&["spotify:track:1", "2"]));
+    }
+
+    #[test]
+    fn quoted_arguments_keep_their_whitespace() {
+        let request = parse(r#"find artist "The  Beatles" album """#).unwrap();
+
+        assert_eq!(request.args, args(&["artist", "The  Beatles", "album", ""]));
+    }
+
+    #[test]
+    fn backslash_escapes_inside_quotes() {
+        let request = parse(r#"find "(title == \"It\\'s \\\\ me\")""#).unwrap();
+
+        assert_eq!(request.args, args(&[r#"(title == "It\'s \\ me")"#]));
+    }
+
+    #[test]
+    fn backslash_is_kept_outside_of_quotes() {
+        let request = parse(r"add a\b").unwrap();
+
+        assert_eq!(request.args, args(&[r"a\b"]));
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert_eq!(parse(r#"find "artist"#), Err(MpdError::Arg("Missing closing '\"'".to_owned())));
+        assert_eq!(parse(r#"find "artist\"#), Err(MpdError::Arg("Missing closing '\"'".to_owned())));
+    }
+
+    #[test]
+    fn closing_quote_must_end_the_argument() {
+        assert_eq!(parse(r#"find "artist"x"#), Err(MpdError::Arg("Space expected after closing '\"'".to_owned())));
+    }
+
+    #[test]
+    fn command_must_be_given_unquoted() {
+        assert!(matches!(parse("   "), Err(MpdError::Unknown(_))));
+        assert!(matches!(parse(r#""status""#), Err(MpdError::Unknown(_))));
+    }
+}
@@ -1,34 +1,105 @@
 use std::sync::{Arc, RwLock, Mutex};
 use crate::track::Track;
 use crate::respot::{PlayerCommand, PlayerEvent};
-use std::cmp::Ordering;
 use futures::task::{Context, Poll};
 use std::pin::Pin;
 use tokio_core::reactor::Core;
 use std::time::{Duration, SystemTime};
-use std::sync::atomic::AtomicU16;
+use std::sync::atomic::{AtomicU16, AtomicBool, AtomicUsize, AtomicU32};
 use futures::channel::mpsc;
+use core::fmt;
+use rand::seq::SliceRandom;
+use std::ops::Range;
+use std::collections::HashSet;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+use crate::mpd::{self, SubsystemEvent};
+
+/// A track in the queue together with the song id MPD clients use to refer to it.
+/// Ids are never reused, so they stay valid while the queue is being edited.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QueueEntry {
+    pub id: usize,
+    pub track: Track,
+    /// Queue version at which this entry was added or last changed position
+    pub version: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SingleMode {
+    Off,
+    On,
+    Oneshot,
+}
+
+impl fmt::Display for SingleMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SingleMode::Off => write!(f, "0"),
+            SingleMode::On => write!(f, "1"),
+            SingleMode::Oneshot => write!(f, "oneshot"),
+        }
+    }
+}
+
+/// Everything needed to pick up where the queue left off after a restart
+#[derive(Deserialize, Serialize)]
+pub struct QueueState {
+    pub entries: Vec<QueueEntry>,
+    pub current: Option<usize>,
+    pub elapsed_ms: u64,
+    /// Whether a track was playing or paused, as opposed to stopped
+    pub active: bool,
+    pub playing: bool,
+    pub volume: u16,
+    pub repeat: bool,
+    pub random: bool,
+    pub single: SingleMode,
+    pub consume: bool,
+}
 
 pub struct Queue {
-    pub queue: Arc<RwLock<Vec<Track>>>,
+    pub queue: Arc<RwLock<Vec<QueueEntry>>>,
+    next_song_id: AtomicUsize,
+    version: AtomicU32,
     current_track: RwLock<Option<usize>>,
+    /// The song id of the track the player has ready to follow the current one
+    preloaded: RwLock<Option<usize>>,
     command_sender: Arc<Mutex<mpsc::UnboundedSender<PlayerCommand>>>,
     status: RwLock<PlayerEvent>,
     elapsed: RwLock<Option<Duration>>,
     since: RwLock<Option<SystemTime>>,
     volume: AtomicU16,
+    repeat: AtomicBool,
+    random: AtomicBool,
+    single: RwLock<SingleMode>,
+    consume: AtomicBool,
+    /// Song ids played since random mode was turned on, so random playback stops once
+    /// every track played when repeat is off
+    played: RwLock<HashSet<usize>>,
+    event_bus: broadcast::Sender<SubsystemEvent>,
 }
 
 impl Queue {
-    pub fn new(command_sender: Arc<Mutex<mpsc::UnboundedSender<PlayerCommand>>>) -> Self {
+    pub fn new(command_sender: Arc<Mutex<mpsc::UnboundedSender<PlayerCommand>>>, event_bus: broadcast::Sender<SubsystemEvent>) -> Self {
         Self {
             queue: Arc::new(RwLock::new(Vec::new())),
+            next_song_id: AtomicUsize::new(1),
+            version: AtomicU32::new(1),
             current_track: RwLock::new(None),
+            preloaded: RwLock::new(None),
             command_sender,
             status: RwLock::new(PlayerEvent::Stopped),
             elapsed: RwLock::new(None),
             since: RwLock::new(None),
             volume: AtomicU16::new(100),
+            repeat: AtomicBool::new(false),
+            random: AtomicBool::new(false),
+            single: RwLock::new(SingleMode::Off),
+            consume: AtomicBool::new(false),
+            played: RwLock::new(HashSet::new()),
+            event_bus,
         }
     }
 
@@ -41,12 +112,83 @@ impl Queue {
         });
     }
 
+    pub fn snapshot(&self) -> QueueState {
+        let status = self.get_status();
+
+        QueueState {
+            entries: self.queue.read().unwrap().clone(),
+            current: self.get_current_index(),
+            elapsed_ms: self.get_current_elapsed_time().as_millis() as u64,
+            active: status == PlayerEvent::Playing || status == PlayerEvent::Paused,
+            playing: status == PlayerEvent::Playing,
+            volume: self.get_volume(),
+            repeat: self.get_repeat(),
+            random: self.get_random(),
+            single: self.get_single(),
+            consume: self.get_consume(),
+        }
+    }
+
+    /// Replaces the queue and its modes with a saved state. The saved song ids are kept,
+    /// a track that was playing is resumed with `resume` and left paused at its position otherwise.
+    pub fn restore(&self, state: QueueState, resume: bool) {
+        let next_song_id = state.entries.iter().map(|entry| entry.id).max().unwrap_or(0) + 1;
+        self.next_song_id.store(next_song_id, std::sync::atomic::Ordering::Relaxed);
+        let version = self.bump_version();
+        {
+            let mut queue = self.queue.write().unwrap();
+            *queue = state.entries;
+            Self::touch_entries(&mut queue, version);
+        }
+        self.notify(SubsystemEvent::Playlist);
+
+        self.set_volume(state.volume);
+        self.set_repeat(state.repeat);
+        self.set_random(state.random);
+        self.set_single(state.single);
+        self.set_consume(state.consume);
+
+        let current = state.current.filter(|index| *index < self.len());
+        *self.current_track.write().unwrap() = current;
+        if let (Some(index), true) = (current, state.active) {
+            let position = Duration::from_millis(state.elapsed_ms);
+            if resume && state.playing {
+                // The player hasn't reported playing yet, so the seek can't wait for it
+                self.play_index_at(index, position);
+            } else {
+                self.load_paused(index, position);
+            }
+        }
+    }
+
+    /// Loads the track at `index` without starting it, `play` continues from `position`
+    fn load_paused(&self, index: usize, position: Duration) {
+        if let Some(entry) = self.get_entry(index) {
+            self.set_elapsed(Some(position));
+            self.set_since(None);
+            self.current_track.write().unwrap().replace(index);
+            debug!("Dispatching load");
+            self.dispatch(PlayerCommand::Load(entry.track));
+            self.forget_preload();
+            self.dispatch(PlayerCommand::Seek(position.as_millis() as u32));
+            debug!("Dispatching pause");
+            self.dispatch(PlayerCommand::Pause);
+        }
+    }
+
     pub fn next_index(&self) -> Option<usize> {
-        match *self.current_track.read().unwrap() {
+        let len = self.len();
+        match self.get_current_index() {
             Some(index) => {
+                if self.get_random() && len > 1 {
+                    return self.random_index(index);
+                }
+
                 let next_index = index + 1;
-                if next_index < self.queue.read().unwrap().len() {
+                if next_index < len {
                     Some(next_index)
+                } else if self.get_repeat() && len > 0 {
+                    Some(0)
                 } else {
                     None
                 }
@@ -55,12 +197,46 @@ impl Queue {
         }
     }
 
+    /// Picks a track other than the current one that didn't play yet in random mode.
+    /// With repeat any other track is fine once they all played, without it playback ends.
+    fn random_index(&self, current_index: usize) -> Option<usize> {
+        let queue = self.queue.read().unwrap();
+        let others = (0..queue.len()).filter(|index| *index != current_index);
+        let unplayed: Vec<usize> = {
+            let played = self.played.read().unwrap();
+            others.clone().filter(|index| !played.contains(&queue[*index].id)).collect()
+        };
+
+        let candidates = if !unplayed.is_empty() {
+            unplayed
+        } else if self.get_repeat() {
+            others.collect()
+        } else {
+            return None;
+        };
+
+        candidates.choose(&mut rand::thread_rng()).cloned()
+    }
+
+    /// Remembers the track as played for random mode. With repeat on, a new round starts
+    /// once every track in the queue played.
+    fn mark_played(&self, queue: &[QueueEntry], song_id: usize) {
+        let mut played = self.played.write().unwrap();
+        played.insert(song_id);
+        if self.get_repeat() && queue.iter().all(|entry| played.contains(&entry.id)) {
+            played.clear();
+            played.insert(song_id);
+        }
+    }
+
     pub fn previous_index(&self) -> Option<usize> {
-        match *self.current_track.read().unwrap() {
+        match self.get_current_index() {
             Some(index) => {
                 if index > 0 {
                     let next_index = index - 1;
                     Some(next_index)
+                } else if self.get_repeat() && !self.is_empty() {
+                    Some(self.len() - 1)
                 } else {
                     None
                 }
@@ -69,96 +245,301 @@ impl Queue {
         }
     }
 
+    /// Only ever locks the current track briefly, it is never held while taking the queue lock.
+    /// The queue lock comes first wherever both are held.
     pub fn get_current_index(&self) -> Option<usize> {
-        match *self.current_track.read().unwrap() {
-            Some(index) => Some(index),
-            None => None,
-        }
+        *self.current_track.read().unwrap()
     }
 
     pub fn get_current(&self) -> Option<Track> {
-        match *self.current_track.read().unwrap() {
-            Some(index) => Some(self.queue.read().unwrap()[index].clone()),
-            None => None,
-        }
+        self.get_current_entry().map(|entry| entry.track)
+    }
+
+    pub fn get_current_entry(&self) -> Option<QueueEntry> {
+        let index = self.get_current_index()?;
+
+        self.get_entry(index)
+    }
+
+    pub fn get_entry(&self, index: usize) -> Option<QueueEntry> {
+        self.queue.read().unwrap().get(index).cloned()
+    }
+
+    pub fn get_index_by_id(&self, song_id: usize) -> Option<usize> {
+        self.queue
+            .read()
+            .unwrap()
+            .iter()
+            .position(|entry| entry.id == song_id)
     }
 
     pub fn append(&self, track: &Track) -> usize {
-        let mut queue = self.queue.write().unwrap();
-        queue.push(track.clone());
+        let song_id = self.next_song_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.queue.write().unwrap().push(QueueEntry {
+            id: song_id,
+            track: track.clone(),
+            version: self.bump_version(),
+        });
         debug!("New track appended to queue");
+        self.notify(SubsystemEvent::Playlist);
+        self.update_preload();
 
-        queue.len() - 1
+        song_id
     }
 
-    pub fn remove(&self, index: usize) {
-        {
+    /// Inserts a track at `index` and returns its song id
+    pub fn insert(&self, track: &Track, index: usize) -> Option<usize> {
+        let song_id = {
             let mut queue = self.queue.write().unwrap();
-            queue.remove(index);
+            if index > queue.len() {
+                return None;
+            }
+
+            let song_id = self.next_song_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let version = self.bump_version();
+            queue.insert(index, QueueEntry {
+                id: song_id,
+                track: track.clone(),
+                version,
+            });
+            Self::touch_entries(&mut queue[index..], version);
+
+            song_id
+        };
+        debug!("New track inserted into queue at {}", index);
+        self.notify(SubsystemEvent::Playlist);
+
+        let mut current = self.current_track.write().unwrap();
+        if let Some(current_track) = *current {
+            if current_track >= index {
+                current.replace(current_track + 1);
+            }
+        }
+        drop(current);
+        self.update_preload();
+
+        Some(song_id)
+    }
+
+    pub fn remove(&self, index: usize) {
+        self.remove_range(index..index + 1);
+    }
+
+    pub fn remove_range(&self, range: Range<usize>) -> bool {
+        if !self.remove_entries(range.clone()) {
+            return false;
         }
 
-        if self.queue_is_empty() {
+        if self.is_empty() {
             self.stop();
-            return;
+            return true;
         }
 
-        let current = *self.current_track.read().unwrap();
-        if let Some(current_track) = current {
-            match current_track.cmp(&index) {
-                Ordering::Equal => {
-                    if current_track == self.queue.read().unwrap().len() {
-                        self.stop();
-                    } else {
-                        self.play_id(index);
+        if let Some(current_track) = self.get_current_index() {
+            if range.contains(&current_track) {
+                // The track after the removed ones takes over in the state the removed one was in
+                if range.start == self.len() {
+                    self.stop();
+                } else {
+                    match self.get_status() {
+                        PlayerEvent::Playing => {
+                            self.play_index(range.start);
+                        }
+                        PlayerEvent::Paused => self.load_paused(range.start, Duration::from_secs(0)),
+                        _ => {
+                            self.current_track.write().unwrap().replace(range.start);
+                        }
                     }
                 }
-                Ordering::Greater => {
-                    let mut current = self.current_track.write().unwrap();
-                    current.replace(current_track - 1);
-                }
-                _ => (),
+            } else if current_track >= range.end {
+                let mut current = self.current_track.write().unwrap();
+                current.replace(current_track - range.len());
             }
         }
+        self.update_preload();
+
+        true
     }
 
-    fn queue_is_empty(&self) -> bool {
-        self.queue.read().unwrap().len() == 0
+    /// Removes the entries in `range` and marks every entry that moved up as changed.
+    /// Returns false without removing anything when the range is empty or out of bounds.
+    fn remove_entries(&self, range: Range<usize>) -> bool {
+        let start = range.start;
+        {
+            let mut queue = self.queue.write().unwrap();
+            if range.start >= range.end || range.end > queue.len() {
+                return false;
+            }
+            queue.drain(range);
+            let version = self.bump_version();
+            Self::touch_entries(&mut queue[start..], version);
+        }
+        self.notify(SubsystemEvent::Playlist);
+
+        true
     }
 
     pub fn clear(&self) {
         self.stop();
 
+        {
+            let mut queue = self.queue.write().unwrap();
+            queue.clear();
+            self.bump_version();
+        }
+        self.notify(SubsystemEvent::Playlist);
+    }
+
+    /// Moves the entries in `range` so that the first one ends up at position `to`
+    pub fn move_range(&self, range: Range<usize>, to: usize) -> bool {
+        self.reorder(|queue| {
+            let len = queue.len();
+            let fits = to.checked_add(range.len()).filter(|end| *end <= len).is_some();
+            if range.start >= range.end || range.end > len || !fits {
+                return false;
+            }
+
+            let moved: Vec<QueueEntry> = queue.drain(range).collect();
+            for (offset, entry) in moved.into_iter().enumerate() {
+                queue.insert(to + offset, entry);
+            }
+
+            true
+        })
+    }
+
+    pub fn swap(&self, first: usize, second: usize) -> bool {
+        self.reorder(|queue| {
+            if first >= queue.len() || second >= queue.len() {
+                return false;
+            }
+
+            queue.swap(first, second);
+
+            true
+        })
+    }
+
+    pub fn shuffle(&self, range: Range<usize>) -> bool {
+        self.reorder(|queue| {
+            if range.start > range.end || range.end > queue.len() {
+                return false;
+            }
+
+            queue[range].shuffle(&mut rand::thread_rng());
+
+            true
+        })
+    }
+
+    /// Applies a reordering to the queue, marks every entry that changed position and
+    /// keeps the current track pointing at the entry that is playing. The reordering checks
+    /// its positions itself, against the queue as it is under the lock, and returns false
+    /// without changing anything when they are out of range.
+    fn reorder<F>(&self, reorder: F) -> bool
+        where F: FnOnce(&mut Vec<QueueEntry>) -> bool {
         let mut queue = self.queue.write().unwrap();
-        queue.clear();
+        let previous_ids: Vec<usize> = queue.iter().map(|entry| entry.id).collect();
+        let current_id = self.get_current_index().and_then(|index| previous_ids.get(index).cloned());
+
+        if !reorder(&mut queue) {
+            return false;
+        }
+
+        let version = self.bump_version();
+        for (pos, entry) in queue.iter_mut().enumerate() {
+            if previous_ids[pos] != entry.id {
+                entry.version = version;
+            }
+        }
+
+        if let Some(current_id) = current_id {
+            if let Some(index) = queue.iter().position(|entry| entry.id == current_id) {
+                self.current_track.write().unwrap().replace(index);
+            }
+        }
+        drop(queue);
+
+        self.notify(SubsystemEvent::Playlist);
+        self.update_preload();
+
+        true
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the position and entry of everything that changed after `version`
+    pub fn changes_since(&self, version: u32) -> Vec<(usize, QueueEntry)> {
+        self.queue
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.version > version)
+            .map(|(pos, entry)| (pos, entry.clone()))
+            .collect()
+    }
+
+    fn bump_version(&self) -> u32 {
+        self.version.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1
+    }
+
+    fn touch_entries(entries: &mut [QueueEntry], version: u32) {
+        for entry in entries.iter_mut() {
+            entry.version = version;
+        }
     }
 
     pub fn len(&self) -> usize {
         self.queue.read().unwrap().len()
     }
 
-    pub fn play_id(&self, index: usize) {
-        if let Some(track) = &self.queue.read().unwrap().get(index) {
-            debug!("Dispatching load");
-            self.dispatch(PlayerCommand::Load(track.id.as_ref().unwrap().to_owned()));
-            let mut current = self.current_track.write().unwrap();
-            current.replace(index);
-            debug!("Dispatching play");
-            self.dispatch(PlayerCommand::Play);
-        }
+    pub fn is_empty(&self) -> bool {
+        self.queue.read().unwrap().is_empty()
+    }
+
+    /// Starts the track at `index`, returns false when there is none
+    pub fn play_index(&self, index: usize) -> bool {
+        let queue = self.queue.read().unwrap();
+        let entry = match queue.get(index) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        self.mark_played(&queue, entry.id);
+        self.set_elapsed(None);
+        self.set_since(None);
+        debug!("Dispatching load");
+        self.dispatch(PlayerCommand::Load(entry.track.clone()));
+        self.forget_preload();
+        let mut current = self.current_track.write().unwrap();
+        current.replace(index);
+        debug!("Dispatching play");
+        self.dispatch(PlayerCommand::Play);
+
+        true
     }
 
     pub fn play(&self) {
+        // A stopped player has nothing loaded, so start the current track over
+        if self.get_status() == PlayerEvent::Stopped {
+            self.play_index(self.get_current_index().unwrap_or(0));
+            return;
+        }
+
         debug!("Dispatching play");
         self.dispatch(PlayerCommand::Play);
     }
 
     pub fn toggle_playback(&self) {
-        if self.get_status() == PlayerEvent::Playing {
-            debug!("Dispatching pause");
-            self.dispatch(PlayerCommand::Pause);
-        } else {
-            debug!("Dispatching play");
-            self.dispatch(PlayerCommand::Play);
+        match self.get_status() {
+            PlayerEvent::Playing => {
+                debug!("Dispatching pause");
+                self.dispatch(PlayerCommand::Pause);
+            }
+            _ => self.play(),
         }
     }
 
@@ -167,24 +548,243 @@ impl Queue {
         *current = None;
         debug!("Dispatching stop");
         self.dispatch(PlayerCommand::Stop);
+        self.forget_preload();
+    }
+
+    /// Stops playback but keeps the current track so that `play` starts it over
+    pub fn stop_playback(&self) {
+        debug!("Dispatching stop");
+        self.dispatch(PlayerCommand::Stop);
+        self.forget_preload();
+    }
+
+    /// Seeks in the current track, returns false when there is none or it is stopped
+    pub fn seek(&self, position: Duration) -> bool {
+        self.get_status() != PlayerEvent::Stopped && self.seek_loaded(position)
+    }
+
+    /// Starts the track at `index` from `position`, returns false when there is no such track
+    pub fn play_index_at(&self, index: usize, position: Duration) -> bool {
+        // The player reports playing later, but it has the track loaded already
+        self.play_index(index) && self.seek_loaded(position)
+    }
+
+    /// Seeks in the track the player has loaded, whatever state it is in
+    fn seek_loaded(&self, position: Duration) -> bool {
+        let position = match self.get_current() {
+            Some(track) => std::cmp::min(position, Duration::from_millis(track.duration.into())),
+            None => return false,
+        };
+
+        self.set_elapsed(Some(position));
+        if self.get_status() == PlayerEvent::Playing {
+            self.set_since(Some(SystemTime::now()));
+        } else {
+            self.set_since(None);
+        }
+
+        debug!("Dispatching seek");
+        self.dispatch(PlayerCommand::Seek(position.as_millis() as u32));
+        self.notify(SubsystemEvent::Player);
+
+        true
+    }
+
+    pub fn seek_relative(&self, offset: f64) -> bool {
+        let elapsed = self.get_current_elapsed_time().as_secs_f64();
+        let position = (elapsed + offset).max(0.0);
+
+        self.seek(Duration::from_secs_f64(position))
     }
 
     pub fn next(&self) {
-        if let Some(index) = self.next_index() {
-            self.play_id(index);
+        let next_index = self.next_index();
+        match self.consume_current(next_index) {
+            Some(index) => {
+                self.play_index(index);
+            }
+            None => self.stop(),
+        }
+    }
+
+    /// Called when the player reports that the current track has finished playing,
+    /// follows the single, repeat and consume modes to decide what to play next.
+    pub fn end_of_track(&self) {
+        match self.get_single() {
+            SingleMode::Off => self.next(),
+            single => {
+                if single == SingleMode::Oneshot {
+                    self.set_single(SingleMode::Off);
+                }
+
+                if self.get_repeat() && single == SingleMode::On {
+                    let current_index = self.get_current_index();
+                    match self.consume_current(current_index) {
+                        Some(index) => {
+                            self.play_index(index);
+                        }
+                        None => self.stop(),
+                    }
+                } else {
+                    self.consume_current(None);
+                    self.stop();
+                }
+            }
+        }
+    }
+
+    /// Called when the player went on with the preloaded track by itself, moves the
+    /// current track along the way `end_of_track` would have.
+    pub fn preloaded_started(&self) {
+        // Without a preload the player can't have moved on by itself
+        let song_id = match self.preloaded.write().unwrap().take() {
+            Some(song_id) => song_id,
+            None => return,
+        };
+
+        match self.get_index_by_id(song_id) {
+            Some(index) => match self.consume_current(Some(index)) {
+                Some(index) => {
+                    self.current_track.write().unwrap().replace(index);
+                    self.mark_played(&self.queue.read().unwrap(), song_id);
+                }
+                None => self.stop(),
+            },
+            // The preloaded track left the queue just as it started, so the one after the current plays instead
+            None => self.next(),
+        }
+    }
+
+    /// The track `end_of_track` is going to continue with
+    fn planned_next_index(&self) -> Option<usize> {
+        let current_index = self.get_current_index()?;
+        let next_index = match self.get_single() {
+            SingleMode::Off => self.next_index(),
+            SingleMode::On if self.get_repeat() => Some(current_index),
+            _ => None,
+        };
+
+        // A consumed track is gone before it could be played again
+        next_index.filter(|index| !(self.get_consume() && *index == current_index))
+    }
+
+    /// Tells the player which track follows the current one, so it can start it without
+    /// a gap. Called after everything that may change what comes next.
+    fn update_preload(&self) {
+        let status = self.get_status();
+        let next_entry = if status == PlayerEvent::Playing || status == PlayerEvent::Paused {
+            self.planned_next_index().and_then(|index| self.get_entry(index))
         } else {
-            self.stop();
+            None
+        };
+
+        let preloaded = *self.preloaded.read().unwrap();
+        // Keep the track random picked while it is still queued, rather than picking again on every edit
+        if let (Some(song_id), Some(_), true) = (preloaded, &next_entry, self.get_random()) {
+            let current_id = self.get_current_entry().map(|entry| entry.id);
+            if current_id != Some(song_id) && self.get_index_by_id(song_id).is_some() {
+                return;
+            }
+        }
+
+        match next_entry {
+            Some(entry) if preloaded != Some(entry.id) => {
+                self.preloaded.write().unwrap().replace(entry.id);
+                debug!("Dispatching preload");
+                self.dispatch(PlayerCommand::Preload(entry.track));
+            }
+            None if preloaded.is_some() => {
+                self.forget_preload();
+                debug!("Dispatching cancel preload");
+                self.dispatch(PlayerCommand::CancelPreload);
+            }
+            _ => (),
+        }
+    }
+
+    /// For after loading or stopping, which drops the preloaded track in the player
+    fn forget_preload(&self) {
+        self.preloaded.write().unwrap().take();
+    }
+
+    /// Removes the current track if consume is enabled and returns `next_index`
+    /// adjusted for the removal.
+    fn consume_current(&self, next_index: Option<usize>) -> Option<usize> {
+        if !self.get_consume() {
+            return next_index;
+        }
+
+        let current_index = match self.get_current_index() {
+            Some(index) => index,
+            None => return next_index,
+        };
+
+        self.remove_entries(current_index..current_index + 1);
+        self.current_track.write().unwrap().take();
+
+        match next_index {
+            Some(index) if index == current_index => None,
+            Some(index) if index > current_index => Some(index - 1),
+            index => index,
         }
     }
 
     pub fn previous(&self) {
-        if let Some(index) = self.previous_index() {
-            self.play_id(index);
-        } else {
-            self.dispatch(PlayerCommand::Stop);
+        // Without a song before it, the current song starts over like it does in MPD
+        if let Some(index) = self.previous_index().or_else(|| self.get_current_index()) {
+            self.play_index(index);
         }
     }
 
+    pub fn get_repeat(&self) -> bool {
+        self.repeat.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_repeat(&self, repeat: bool) {
+        self.repeat.store(repeat, std::sync::atomic::Ordering::Relaxed);
+        self.notify(SubsystemEvent::Options);
+        self.update_preload();
+    }
+
+    pub fn get_random(&self) -> bool {
+        self.random.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_random(&self, random: bool) {
+        self.random.store(random, std::sync::atomic::Ordering::Relaxed);
+        // Every track gets its turn again, the current one counts as played
+        let current_id = self.get_current_entry().map(|entry| entry.id);
+        let mut played = self.played.write().unwrap();
+        played.clear();
+        played.extend(current_id);
+        drop(played);
+        self.notify(SubsystemEvent::Options);
+        self.update_preload();
+    }
+
+    pub fn get_single(&self) -> SingleMode {
+        *self.single.read().expect("unable to get read lock")
+    }
+
+    pub fn set_single(&self, single: SingleMode) {
+        {
+            let mut current = self.single.write().expect("unable to get write lock");
+            *current = single;
+        }
+        self.notify(SubsystemEvent::Options);
+        self.update_preload();
+    }
+
+    pub fn get_consume(&self) -> bool {
+        self.consume.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_consume(&self, consume: bool) {
+        self.consume.store(consume, std::sync::atomic::Ordering::Relaxed);
+        self.notify(SubsystemEvent::Options);
+        self.update_preload();
+    }
+
     pub fn get_status(&self) -> PlayerEvent {
         let status = self.status.read().expect("unable to get read lock");
 
@@ -215,6 +815,7 @@ impl Queue {
         debug!("Dispatching set volume");
         self.volume.store(vol, std::sync::atomic::Ordering::Relaxed);
         self.dispatch(PlayerCommand::SetVolume(vol));
+        self.notify(SubsystemEvent::Mixer);
     }
 
     fn set_elapsed(&self, new_elapsed: Option<Duration>) {
@@ -252,6 +853,10 @@ impl Queue {
     fn dispatch(&self, command: PlayerCommand) {
         self.command_sender.lock().unwrap().unbounded_send(command).unwrap();
     }
+
+    fn notify(&self, event: SubsystemEvent) {
+        mpd::notify(&self.event_bus, event);
+    }
 }
 
 struct QueueWorker {
@@ -281,7 +886,13 @@ impl QueueWorker {
                 debug!("Finished track!");
                 self.queue.set_elapsed(None);
                 self.queue.set_since(None);
-                self.queue.next();
+                self.queue.end_of_track();
+            }
+            PlayerEvent::PreloadedStarted => {
+                debug!("Finished track, continuing with the preloaded one");
+                self.queue.set_elapsed(None);
+                self.queue.set_since(Some(SystemTime::now()));
+                self.queue.preloaded_started();
             }
             PlayerEvent::Stopped => {
                 self.queue.set_elapsed(None);
@@ -289,8 +900,21 @@ impl QueueWorker {
             }
         }
 
-        let mut status = self.queue.status.write().expect("unable to get write lock");
-        *status = event;
+        let status = match event {
+            PlayerEvent::PreloadedStarted => PlayerEvent::Playing,
+            event => event,
+        };
+        {
+            let mut status_lock = self.queue.status.write().expect("unable to get write lock");
+            *status_lock = status.clone();
+        }
+
+        // A track is loaded now, so the player can get the next one ready. This happens before
+        // clients hear about the change, so the next track is on its way once they do.
+        if status == PlayerEvent::Playing || status == PlayerEvent::Paused {
+            self.queue.update_preload();
+        }
+        self.queue.notify(SubsystemEvent::Player);
     }
 }
 
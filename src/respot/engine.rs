@@ -0,0 +1,34 @@
+use crate::track::Track;
+use futures::Future;
+use std::pin::Pin;
+
+/// Completes once the loaded track played to its end, or fails when it was stopped
+/// or replaced by another track first.
+pub type EndOfTrack = Pin<Box<dyn Future<Output=Result<(), ()>>>>;
+
+/// The audio output the player worker drives with the `PlayerCommand`s it receives,
+/// the worker reports the resulting `PlayerEvent`s back to the queue.
+pub trait AudioEngine {
+    /// Loads the track paused at its start
+    fn load(&mut self, track: &Track) -> EndOfTrack;
+
+    fn play(&mut self);
+
+    fn pause(&mut self);
+
+    fn stop(&mut self);
+
+    fn seek(&mut self, position_ms: u32);
+
+    /// Prepares the track that follows the current one, so `play_preloaded` can start it
+    /// without a gap. Replaces an earlier preload, `load` and `stop` drop it.
+    fn preload(&mut self, track: &Track);
+
+    fn cancel_preload(&mut self);
+
+    /// Starts the preloaded track right away, the worker only calls this after a preload
+    fn play_preloaded(&mut self) -> EndOfTrack;
+
+    /// Sets the volume from 0 to 100
+    fn set_volume(&mut self, volume: u16);
+}
@@ -0,0 +1,136 @@
+use crate::respot::engine::{AudioEngine, EndOfTrack};
+use crate::track::Track;
+use futures::compat::Future01CompatExt;
+use futures::FutureExt;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::audio_backend;
+use librespot::playback::config::Bitrate::Bitrate320;
+use librespot::playback::config::PlayerConfig;
+use librespot::playback::mixer::Mixer;
+use librespot::playback::player::Player;
+
+/// Streams tracks from Spotify with librespot to the default audio backend. The player
+/// of librespot 0.1 can't queue tracks, so a second one on standby loads the next track
+/// paused and takes over when the current one ends. A player only starts its sink while
+/// it plays and stops it before reporting the end of its track, so the two never hold
+/// the audio device at the same time.
+pub struct LibrespotEngine {
+    players: [Player; 2],
+    /// The index of the player that plays the current track
+    active: usize,
+    /// The end of the track loaded by the standby player
+    preloaded: Option<EndOfTrack>,
+    mixer: Box<dyn Mixer>,
+}
+
+impl LibrespotEngine {
+    pub fn new(session: Session) -> Self {
+        let create_mixer = librespot::playback::mixer::find(Some("softvol".to_owned()))
+            .expect("Unable to find softvol mixer");
+        let mixer = create_mixer(None);
+
+        let create_player = || {
+            let player_config = PlayerConfig {
+                bitrate: Bitrate320,
+                ..Default::default()
+            };
+            let backend = audio_backend::find(None).unwrap();
+            let (player, _) = Player::new(player_config, session.clone(), mixer.get_audio_filter(), move || {
+                (backend)(None)
+            });
+
+            player
+        };
+
+        Self {
+            players: [create_player(), create_player()],
+            active: 0,
+            preloaded: None,
+            mixer,
+        }
+    }
+
+    fn player(&self) -> &Player {
+        &self.players[self.active]
+    }
+
+    fn standby_player(&self) -> &Player {
+        &self.players[1 - self.active]
+    }
+
+    fn load_track(player: &Player, track: &Track) -> EndOfTrack {
+        // Unlike the bare id, the URI tells tracks and podcast episodes apart
+        let uri = SpotifyId::from_uri(&track.url).unwrap();
+
+        Box::pin(player.load(uri, false, 0).compat().map(|result| result.map_err(|_| ())))
+    }
+
+    fn calc_logarithmic_volume(volume: u16) -> u16 {
+        let mixer_volume = ((std::cmp::min(volume, 100) as f32) / 100.0 * 65535_f32).ceil() as u16;
+        // Volume conversion taken from https://github.com/plietar/librespot/blob/master/src/spirc.rs
+        const IDEAL_FACTOR: f64 = 6.908;
+        let normalized_volume = mixer_volume as f64 / u16::MAX as f64;
+
+        let val = if normalized_volume < 0.999 {
+            let new_volume = (normalized_volume * IDEAL_FACTOR).exp() / 1000.0;
+            (new_volume * u16::MAX as f64) as u16
+        } else {
+            u16::MAX
+        };
+
+        debug!("input volume:{} to mixer: {}", volume, val);
+
+        val
+    }
+}
+
+impl AudioEngine for LibrespotEngine {
+    fn load(&mut self, track: &Track) -> EndOfTrack {
+        self.cancel_preload();
+
+        Self::load_track(self.player(), track)
+    }
+
+    fn play(&mut self) {
+        self.player().play();
+    }
+
+    fn pause(&mut self) {
+        self.player().pause();
+    }
+
+    fn stop(&mut self) {
+        self.cancel_preload();
+        self.player().stop();
+    }
+
+    fn seek(&mut self, position_ms: u32) {
+        self.player().seek(position_ms);
+    }
+
+    fn preload(&mut self, track: &Track) {
+        self.preloaded = Some(Self::load_track(self.standby_player(), track));
+    }
+
+    fn cancel_preload(&mut self) {
+        if self.preloaded.take().is_some() {
+            self.standby_player().stop();
+        }
+    }
+
+    fn play_preloaded(&mut self) -> EndOfTrack {
+        match self.preloaded.take() {
+            Some(end_of_track) => {
+                self.active = 1 - self.active;
+                self.player().play();
+                end_of_track
+            }
+            None => Box::pin(futures::future::pending()),
+        }
+    }
+
+    fn set_volume(&mut self, volume: u16) {
+        self.mixer.set_volume(Self::calc_logarithmic_volume(volume));
+    }
+}
@@ -1,23 +1,29 @@
 pub mod player_worker;
+mod engine;
+mod librespot_engine;
+mod simulated_engine;
+
+pub use crate::respot::engine::{AudioEngine, EndOfTrack};
+pub use crate::respot::librespot_engine::LibrespotEngine;
+pub use crate::respot::simulated_engine::{SimulatedEngine, VirtualClock};
 
 use tokio_core::reactor::Core;
-use librespot::playback::config::PlayerConfig;
-use librespot::playback::audio_backend;
 use futures_01::{Future, Async, Stream};
-use librespot::core::session::Session;
 use std::thread;
 use tokio_signal::IoStream;
-use librespot::playback::player::Player;
 use crate::respot::player_worker::PlayerWorker;
+use crate::track::Track;
 use core::fmt;
-use librespot::playback::config::Bitrate::Bitrate320;
 use futures::channel::mpsc;
 use std::pin::Pin;
 use futures::task::{Context, Poll};
 
 #[derive(Debug)]
 pub enum PlayerCommand {
-    Load(String),
+    Load(Track),
+    /// The track to continue with once the current one ends
+    Preload(Track),
+    CancelPreload,
     Seek(u32),
     SetVolume(u16),
     Stop,
@@ -28,6 +34,8 @@ pub enum PlayerCommand {
 #[derive(Clone, Debug, PartialEq)]
 pub enum PlayerEvent {
     EndOfTrack,
+    /// The current track ended and the preloaded one started playing
+    PreloadedStarted,
     Playing,
     Stopped,
     Paused,
@@ -45,34 +53,51 @@ impl fmt::Display for PlayerEvent {
 
 // Todo: How can we get a futures 0.3 compatible IoStream?
 pub struct Respot {
-    cancel_signal: IoStream<()>
+    cancel_signal: IoStream<()>,
+    /// Runs on Ctrl-C or SIGTERM before the process exits
+    on_shutdown: Option<Box<dyn FnOnce()>>,
 }
 
 impl Respot {
-    pub fn new(session: Session, command_receiver: mpsc::UnboundedReceiver<PlayerCommand>, event_sender: std::sync::mpsc::Sender<PlayerEvent>) -> Self {
+    /// Starts the player thread, the engine is created on that thread with `create_engine`
+    pub fn new<F>(create_engine: F, command_receiver: mpsc::UnboundedReceiver<PlayerCommand>, event_sender: std::sync::mpsc::Sender<PlayerEvent>) -> Self
+        where F: FnOnce() -> Box<dyn AudioEngine> + Send + 'static {
         let respot = Self {
-            cancel_signal: Box::new(tokio_signal::ctrl_c().flatten_stream())
+            cancel_signal: Self::shutdown_signal(),
+            on_shutdown: None,
         };
-        Self::start_player(session, command_receiver, event_sender);
+        Self::start_player(create_engine, command_receiver, event_sender);
 
         respot
     }
 
-    fn start_player(session: Session, command_receiver: mpsc::UnboundedReceiver<PlayerCommand>, event_sender: std::sync::mpsc::Sender<PlayerEvent>) {
-        thread::spawn(move || {
-            let create_mixer = librespot::playback::mixer::find(Some("softvol".to_owned()))
-                .expect("Unable to find softvol mixer");
-            let mixer = create_mixer(None);
+    /// Ctrl-C, and on Unix the SIGTERM service managers stop the daemon with
+    fn shutdown_signal() -> IoStream<()> {
+        let ctrl_c = tokio_signal::ctrl_c().flatten_stream();
+
+        #[cfg(unix)]
+        {
+            let terminate = tokio_signal::unix::Signal::new(tokio_signal::unix::SIGTERM)
+                .flatten_stream()
+                .map(|_| ());
+            Box::new(ctrl_c.select(terminate))
+        }
+        #[cfg(not(unix))]
+        {
+            Box::new(ctrl_c)
+        }
+    }
 
-            let mut player_config = PlayerConfig::default();
-            player_config.bitrate = Bitrate320;
-            let backend = audio_backend::find(None).unwrap();
-            let (player, _) = Player::new(player_config, session.clone(), mixer.get_audio_filter(), move || {
-                (backend)(None)
-            });
+    pub fn on_shutdown<F: FnOnce() + 'static>(mut self, on_shutdown: F) -> Self {
+        self.on_shutdown = Some(Box::new(on_shutdown));
+        self
+    }
 
+    fn start_player<F>(create_engine: F, command_receiver: mpsc::UnboundedReceiver<PlayerCommand>, event_sender: std::sync::mpsc::Sender<PlayerEvent>)
+        where F: FnOnce() -> Box<dyn AudioEngine> + Send + 'static {
+        thread::spawn(move || {
             let mut core = Core::new().unwrap();
-            let player_worker = PlayerWorker::new(player, mixer, command_receiver, event_sender);
+            let player_worker = PlayerWorker::new(create_engine(), command_receiver, event_sender);
 
             debug!("Connected");
             core.run(futures::compat::Compat::new(player_worker)).unwrap();
@@ -86,7 +111,10 @@ impl futures::Future for Respot {
     fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
         loop {
             if let Async::Ready(Some(())) = self.cancel_signal.poll().unwrap() {
-                debug!("Ctrl-C received");
+                debug!("Shutdown signal received");
+                if let Some(on_shutdown) = self.on_shutdown.take() {
+                    on_shutdown();
+                }
                 std::process::exit(0);
             };
 
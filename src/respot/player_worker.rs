@@ -1,83 +1,74 @@
-use crate::respot::{PlayerCommand, PlayerEvent};
-use librespot::playback::player::Player;
-use librespot::core::spotify_id::SpotifyId;
+use crate::respot::{AudioEngine, EndOfTrack, PlayerCommand, PlayerEvent};
 use futures::task::{Context, Poll};
 use std::pin::Pin;
-use librespot::playback::mixer::Mixer;
 use futures::channel::mpsc;
-use futures::{Stream, Future};
-use futures::compat::Future01CompatExt;
-use futures_01::sync::oneshot::Canceled;
+use futures::Stream;
 
 pub struct PlayerWorker {
-    player: Player,
+    engine: Box<dyn AudioEngine>,
     command_receiver: Pin<Box<mpsc::UnboundedReceiver<PlayerCommand>>>,
     event_sender: std::sync::mpsc::Sender<PlayerEvent>,
-    play_task: Pin<Box<dyn Future<Output=Result<(), Canceled>>>>,
+    play_task: EndOfTrack,
     active: bool,
-    mixer: Box<dyn Mixer>,
+    /// Whether the engine has the next track ready
+    preloaded: bool,
 }
 
 impl PlayerWorker {
-    pub fn new(player: Player, mixer: Box<dyn Mixer>, command_receiver: mpsc::UnboundedReceiver<PlayerCommand>, event_sender: std::sync::mpsc::Sender<PlayerEvent>) -> Self {
+    pub fn new(engine: Box<dyn AudioEngine>, command_receiver: mpsc::UnboundedReceiver<PlayerCommand>, event_sender: std::sync::mpsc::Sender<PlayerEvent>) -> Self {
         Self {
-            player,
+            engine,
             command_receiver: Box::pin(command_receiver),
             event_sender,
             play_task: Box::pin(futures::future::pending()),
             active: false,
-            mixer,
+            preloaded: false,
         }
     }
     fn handle_event(&mut self, event: PlayerCommand) {
         match event {
-            PlayerCommand::Load(id) => {
-                let uri = SpotifyId::from_base62(&id).unwrap();
-
-                self.play_task = Box::pin(self.player.load(uri, false, 0).compat());
-                info!("Loaded track {:?}", id);
+            PlayerCommand::Load(track) => {
+                self.play_task = self.engine.load(&track);
+                self.preloaded = false;
+                info!("Loaded track {:?}", track.id);
+            }
+            PlayerCommand::Preload(track) => {
+                self.engine.preload(&track);
+                self.preloaded = true;
+                info!("Preloaded track {:?}", track.id);
+            }
+            PlayerCommand::CancelPreload => {
+                self.engine.cancel_preload();
+                self.preloaded = false;
             }
             PlayerCommand::Play => {
-                self.player.play();
+                self.engine.play();
                 self.event_sender.send(PlayerEvent::Playing).unwrap();
                 self.active = true;
                 info!("Starting playback");
             }
             PlayerCommand::Pause => {
-                self.player.pause();
+                self.engine.pause();
                 self.event_sender.send(PlayerEvent::Paused).unwrap();
                 self.active = false;
                 info!("pausing playback");
             }
             PlayerCommand::Stop => {
-                self.player.stop();
+                self.engine.stop();
+                self.preloaded = false;
+                self.event_sender.send(PlayerEvent::Stopped).unwrap();
                 self.active = false;
                 info!("Stopping playback");
             }
+            PlayerCommand::Seek(position_ms) => {
+                self.engine.seek(position_ms);
+                info!("Seeking to {}ms", position_ms);
+            }
             PlayerCommand::SetVolume(vol) => {
-                self.mixer.set_volume(Self::calc_logarithmic_volume(vol));
+                self.engine.set_volume(vol);
             }
-            _ => {}
         }
     }
-
-    fn calc_logarithmic_volume(volume: u16) -> u16 {
-        let mixer_volume = ((std::cmp::min(volume, 100) as f32) / 100.0 * 65535_f32).ceil() as u16;
-        // Volume conversion taken from https://github.com/plietar/librespot/blob/master/src/spirc.rs
-        const IDEAL_FACTOR: f64 = 6.908;
-        let normalized_volume = mixer_volume as f64 / std::u16::MAX as f64;
-
-        let val = if normalized_volume < 0.999 {
-            let new_volume = (normalized_volume * IDEAL_FACTOR).exp() / 1000.0;
-            (new_volume * std::u16::MAX as f64) as u16
-        } else {
-            std::u16::MAX
-        };
-
-        debug!("input volume:{} to mixer: {}", volume, val);
-
-        val
-    }
 }
 
 impl futures::Future for PlayerWorker {
@@ -97,9 +88,18 @@ impl futures::Future for PlayerWorker {
                 Poll::Ready(Ok(())) => {
                     debug!("player: PlayerState::EndOfTrack");
                     progress = true;
-                    self.event_sender.send(PlayerEvent::EndOfTrack).unwrap();
+                    // Going on with the preloaded track here, instead of waiting for the
+                    // queue to load it, is what keeps the transition gapless
+                    if self.preloaded {
+                        self.preloaded = false;
+                        self.play_task = self.engine.play_preloaded();
+                        self.event_sender.send(PlayerEvent::PreloadedStarted).unwrap();
+                    } else {
+                        self.play_task = Box::pin(futures::future::pending());
+                        self.event_sender.send(PlayerEvent::EndOfTrack).unwrap();
+                    }
                 }
-                Poll::Ready(Err(Canceled)) => {
+                Poll::Ready(Err(())) => {
                     debug!("player task cancelled");
                     self.play_task = Box::pin(futures::future::pending());
                 }
@@ -111,4 +111,4 @@ impl futures::Future for PlayerWorker {
             }
         }
     }
-}
\ No newline at end of file
+}
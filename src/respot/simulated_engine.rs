@@ -0,0 +1,217 @@
+use crate::respot::engine::{AudioEngine, EndOfTrack};
+use crate::track::Track;
+use futures::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a clock following the wall clock moves forward
+const REAL_TIME_TICK: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct ClockState {
+    now: Duration,
+    wakers: Vec<Waker>,
+}
+
+/// A clock that only moves when told to, so simulated playback is deterministic
+#[derive(Clone, Default)]
+pub struct VirtualClock(Arc<Mutex<ClockState>>);
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The time passed since the clock was created
+    pub fn now(&self) -> Duration {
+        self.0.lock().unwrap().now
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let wakers = {
+            let mut state = self.0.lock().unwrap();
+            state.now += duration;
+            std::mem::take(&mut state.wakers)
+        };
+
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Moves the clock along with the wall clock from a background thread
+    pub fn follow_real_time(&self) {
+        let clock = self.clone();
+        thread::spawn(move || {
+            let mut last_tick = Instant::now();
+            loop {
+                thread::sleep(REAL_TIME_TICK);
+                let now = Instant::now();
+                clock.advance(now - last_tick);
+                last_tick = now;
+            }
+        });
+    }
+
+    /// Lets everything waiting on the clock look at the playback state again
+    fn wake(&self) {
+        self.advance(Duration::from_secs(0));
+    }
+
+    fn register(&self, waker: &Waker) {
+        self.0.lock().unwrap().wakers.push(waker.clone());
+    }
+}
+
+#[derive(Default)]
+struct Playback {
+    /// Counts up on every load and stop, so the end of an unloaded track is never reported
+    generation: u64,
+    duration: Duration,
+    /// The position at `since`, or the current position while paused
+    position: Duration,
+    /// The clock time playback was last started or moved at, `None` while paused
+    since: Option<Duration>,
+    /// The clock time the last track played to its end at
+    ended_at: Option<Duration>,
+}
+
+impl Playback {
+    fn position(&self, now: Duration) -> Duration {
+        match self.since {
+            Some(since) => self.position + (now - since),
+            None => self.position,
+        }
+    }
+}
+
+/// Pretends to play tracks on a virtual clock, for running without Spotify or sound hardware.
+/// A track ends once the clock moved past its duration while it was playing.
+pub struct SimulatedEngine {
+    clock: VirtualClock,
+    playback: Arc<Mutex<Playback>>,
+    preloaded: Option<Track>,
+}
+
+impl SimulatedEngine {
+    pub fn new(clock: VirtualClock) -> Self {
+        Self {
+            clock,
+            playback: Arc::new(Mutex::new(Playback::default())),
+            preloaded: None,
+        }
+    }
+
+    fn update<F: FnOnce(&mut Playback, Duration)>(&self, f: F) {
+        let now = self.clock.now();
+        f(&mut self.playback.lock().unwrap(), now);
+        self.clock.wake();
+    }
+}
+
+impl AudioEngine for SimulatedEngine {
+    fn load(&mut self, track: &Track) -> EndOfTrack {
+        self.preloaded = None;
+        let mut generation = 0;
+        self.update(|playback, _| {
+            playback.generation += 1;
+            playback.duration = Duration::from_millis(track.duration.into());
+            playback.position = Duration::from_secs(0);
+            playback.since = None;
+            generation = playback.generation;
+        });
+
+        Box::pin(TrackEnd {
+            clock: self.clock.clone(),
+            playback: Arc::clone(&self.playback),
+            generation,
+        })
+    }
+
+    fn play(&mut self) {
+        self.update(|playback, now| {
+            if playback.since.is_none() {
+                playback.since = Some(now);
+            }
+        });
+    }
+
+    fn pause(&mut self) {
+        self.update(|playback, now| {
+            playback.position = playback.position(now);
+            playback.since = None;
+        });
+    }
+
+    fn stop(&mut self) {
+        self.preloaded = None;
+        self.update(|playback, _| {
+            playback.generation += 1;
+            playback.position = Duration::from_secs(0);
+            playback.since = None;
+        });
+    }
+
+    fn seek(&mut self, position_ms: u32) {
+        self.update(|playback, now| {
+            playback.position = Duration::from_millis(position_ms.into());
+            if playback.since.is_some() {
+                playback.since = Some(now);
+            }
+        });
+    }
+
+    fn preload(&mut self, track: &Track) {
+        self.preloaded = Some(track.clone());
+    }
+
+    fn cancel_preload(&mut self) {
+        self.preloaded = None;
+    }
+
+    /// Starts the preloaded track at the moment the previous one ended, as if there was no gap
+    fn play_preloaded(&mut self) -> EndOfTrack {
+        match self.preloaded.take() {
+            Some(track) => {
+                let end_of_track = self.load(&track);
+                self.update(|playback, now| playback.since = Some(playback.ended_at.unwrap_or(now)));
+                end_of_track
+            }
+            None => Box::pin(futures::future::pending()),
+        }
+    }
+
+    /// There is nothing to play the audio at, the queue keeps track of the volume itself
+    fn set_volume(&mut self, _volume: u16) {}
+}
+
+struct TrackEnd {
+    clock: VirtualClock,
+    playback: Arc<Mutex<Playback>>,
+    generation: u64,
+}
+
+impl Future for TrackEnd {
+    type Output = Result<(), ()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let now = self.clock.now();
+        let mut playback = self.playback.lock().unwrap();
+
+        if playback.generation != self.generation {
+            return Poll::Ready(Err(()));
+        }
+        if let (Some(since), true) = (playback.since, playback.position(now) >= playback.duration) {
+            playback.ended_at = Some(since + playback.duration.checked_sub(playback.position).unwrap_or_default());
+            playback.position = playback.duration;
+            playback.since = None;
+            return Poll::Ready(Ok(()));
+        }
+
+        self.clock.register(cx.waker());
+        Poll::Pending
+    }
+}
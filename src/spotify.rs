@@ -2,10 +2,15 @@ use rspotify::client::Spotify;
 use std::time::{Instant, Duration};
 use rspotify::oauth2::{SpotifyOAuth, SpotifyClientCredentials, TokenInfo};
 use rspotify::util::{process_token, request_token};
+use rspotify::model::page::{Page, CursorBasedPage};
+use futures::{stream, Stream, StreamExt};
+use std::future::Future;
 use std::io;
+use chrono::Utc;
 
+use crate::atomic_file::write_atomically;
 use crate::redirect_uri::redirect_uri_web_server;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 pub const SCOPES: [&str; 13] = [
     "playlist-read-collaborative",
@@ -42,18 +47,160 @@ pub async fn get_token_auto(spotify_oauth: &mut SpotifyOAuth, port: u16) -> Opti
     }
 }
 
-pub fn new_spotify_client(token_info: TokenInfo) -> (Arc<Spotify>, Instant) {
-    let token_expiry = Instant::now()
-        + Duration::from_secs(token_info.expires_in.into())
-        - Duration::from_secs(10);
+/// How long before it expires the access token is refreshed
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+pub fn new_spotify_client(token_info: TokenInfo) -> (Spotify, Instant) {
+    // A cached token may be partly used, its `expires_in` is the lifetime it was issued with
+    let lifetime = match token_info.expires_at {
+        Some(expires_at) => Duration::from_secs((expires_at - Utc::now().timestamp()).max(0) as u64),
+        None => Duration::from_secs(token_info.expires_in.into()),
+    };
+    let token_expiry = Instant::now() + lifetime.checked_sub(REFRESH_MARGIN).unwrap_or_default();
 
     let client_credential = SpotifyClientCredentials::default()
         .token_info(token_info)
         .build();
 
-    let spotify = Arc::new(Spotify::default()
+    let spotify = Spotify::default()
         .client_credentials_manager(client_credential)
-        .build());
+        .build();
 
     (spotify, token_expiry)
-}
\ No newline at end of file
+}
+
+/// The Spotify client everything talking to the Web API shares. Callers take the current
+/// client for each request, so a refreshed token is used as soon as it is swapped in.
+#[derive(Clone)]
+pub struct SharedSpotify(Arc<RwLock<Arc<Spotify>>>);
+
+impl SharedSpotify {
+    pub fn new(spotify: Spotify) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(spotify))))
+    }
+
+    pub fn get(&self) -> Arc<Spotify> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    fn replace(&self, spotify: Spotify) {
+        *self.0.write().unwrap() = Arc::new(spotify);
+    }
+}
+
+/// How long to wait before trying again when refreshing the token failed
+const REFRESH_RETRY: Duration = Duration::from_secs(30);
+
+/// Refreshes the access token ahead of its expiry, writes it to the token cache and
+/// swaps the credentials of the shared client.
+pub struct TokenManager {
+    oauth: SpotifyOAuth,
+    spotify: SharedSpotify,
+    token_info: TokenInfo,
+    token_expiry: Instant,
+}
+
+impl TokenManager {
+    pub fn new(oauth: SpotifyOAuth, spotify: SharedSpotify, token_info: TokenInfo, token_expiry: Instant) -> Self {
+        Self {
+            oauth,
+            spotify,
+            token_info,
+            token_expiry,
+        }
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::time::delay_until(self.token_expiry.into()).await;
+
+            match self.refresh().await {
+                Some(token_expiry) => {
+                    info!("Refreshed Spotify access token");
+                    self.token_expiry = token_expiry;
+                }
+                None => {
+                    warn!("Refreshing Spotify access token failed, retrying in {} seconds", REFRESH_RETRY.as_secs());
+                    self.token_expiry = Instant::now() + REFRESH_RETRY;
+                }
+            }
+        }
+    }
+
+    async fn refresh(&mut self) -> Option<Instant> {
+        let refresh_token = self.token_info.refresh_token.clone()?;
+        let oauth = self.oauth.clone();
+        // rspotify panics when the request can't be sent, which only fails this attempt
+        let token_info = tokio::spawn(async move { oauth.refresh_access_token(&refresh_token).await })
+            .await
+            .unwrap_or_else(|e| {
+                warn!("Refreshing Spotify access token panicked: {}", e);
+                None
+            })?;
+
+        // rspotify truncates the token cache before writing it, so it is written again
+        // atomically to never leave a half written token behind
+        let result = serde_json::to_string(&token_info)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| write_atomically(&self.oauth.cache_path, &contents).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to write refreshed token to {}: {}", self.oauth.cache_path.display(), e);
+        }
+
+        let (spotify, token_expiry) = new_spotify_client(token_info.clone());
+        self.spotify.replace(spotify);
+        self.token_info = token_info;
+
+        Some(token_expiry)
+    }
+}
+
+/// The most items Spotify returns per page for most collections
+pub const PAGE_SIZE: u32 = 50;
+
+/// Streams every item of an offset paged collection, `fetch` is called with the
+/// offset of each page once the items of the previous page are used up.
+pub fn paginate<T, E, F, Fut>(fetch: F) -> impl Stream<Item=Result<T, E>>
+    where F: Fn(u32) -> Fut,
+          Fut: Future<Output=Result<Page<T>, E>>,
+{
+    stream::unfold((fetch, Some(0)), |(fetch, offset)| async move {
+        let offset = offset?;
+        let (items, next_offset) = match fetch(offset).await {
+            Ok(page) => {
+                // An empty page would otherwise be requested forever
+                let next_offset = match page.next {
+                    Some(_) if !page.items.is_empty() => Some(offset + page.items.len() as u32),
+                    _ => None,
+                };
+                (page.items.into_iter().map(Ok).collect::<Vec<_>>(), next_offset)
+            }
+            Err(e) => (vec![Err(e)], None),
+        };
+
+        Some((stream::iter(items), (fetch, next_offset)))
+    }).flatten()
+}
+
+/// Streams every item of a cursor paged collection, `fetch` is called with the
+/// cursor after which the next page starts, `None` for the first page.
+pub fn paginate_cursor<T, E, F, Fut>(fetch: F) -> impl Stream<Item=Result<T, E>>
+    where F: Fn(Option<String>) -> Fut,
+          Fut: Future<Output=Result<CursorBasedPage<T>, E>>,
+{
+    stream::unfold((fetch, Some(None)), |(fetch, cursor)| async move {
+        let cursor = cursor?;
+        let (items, next_cursor) = match fetch(cursor).await {
+            Ok(page) => {
+                let next_cursor = match (page.next, page.cursors.after) {
+                    (Some(_), Some(after)) if !page.items.is_empty() => Some(Some(after)),
+                    _ => None,
+                };
+                (page.items.into_iter().map(Ok).collect::<Vec<_>>(), next_cursor)
+            }
+            Err(e) => (vec![Err(e)], None),
+        };
+
+        Some((stream::iter(items), (fetch, next_cursor)))
+    }).flatten()
+}
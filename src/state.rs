@@ -0,0 +1,83 @@
+use crate::atomic_file::write_atomically;
+use crate::mpd::SubsystemEvent;
+use crate::queue::{Queue, QueueState};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// How long changes are collected before the state is saved, skipping through the queue
+/// or dragging the volume slider changes the state many times a second
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Keeps the queue, the playback position, the volume and the playback modes in a file
+/// like MPD's `state_file`, so a restart picks up where the daemon left off
+#[derive(Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    queue: Arc<Queue>,
+}
+
+impl StateFile {
+    pub fn new(path: &str, queue: Arc<Queue>) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            queue,
+        }
+    }
+
+    /// Restores the saved state, a missing or unreadable state file leaves the queue empty
+    pub fn restore(&self, resume: bool) {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+
+        match serde_json::from_str::<QueueState>(&contents) {
+            Ok(state) => {
+                info!("Restoring {} queued tracks from {}", state.entries.len(), self.path.display());
+                self.queue.restore(state, resume);
+            }
+            Err(e) => warn!("Unable to read state file {}: {}", self.path.display(), e),
+        }
+    }
+
+    pub fn save(&self) {
+        let result = serde_json::to_string(&self.queue.snapshot())
+            .map_err(|e| e.to_string())
+            .and_then(|contents| write_atomically(&self.path, &contents).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Unable to write state file {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Saves the state shortly after the queue, the player, the volume or a playback mode changed
+    pub async fn run(self, mut event_receiver: broadcast::Receiver<SubsystemEvent>) {
+        loop {
+            match event_receiver.recv().await {
+                Ok(SubsystemEvent::Playlist)
+                | Ok(SubsystemEvent::Player)
+                | Ok(SubsystemEvent::Mixer)
+                | Ok(SubsystemEvent::Options)
+                | Err(broadcast::RecvError::Lagged(_)) => (),
+                Ok(_) => continue,
+                Err(broadcast::RecvError::Closed) => break,
+            }
+
+            tokio::time::delay_for(SAVE_DELAY).await;
+            // The snapshot taken now covers everything that changed in the meantime
+            loop {
+                match event_receiver.try_recv() {
+                    Err(broadcast::TryRecvError::Empty) | Err(broadcast::TryRecvError::Closed) => break,
+                    _ => (),
+                }
+            }
+
+            let state_file = self.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || state_file.save()).await {
+                warn!("Unable to write state file {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
@@ -1,8 +1,10 @@
-use rspotify::model::track::FullTrack;
+use rspotify::model::track::{FullTrack, SimplifiedTrack};
+use rspotify::model::show::{FullEpisode, SimplifiedEpisode};
+use crate::catalog::{Album, Show};
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Track {
     pub id: Option<String>,
     pub title: String,
@@ -19,7 +21,7 @@ pub struct Track {
 }
 
 impl Track {
-    pub fn to_mpd_format(&self, pos: usize) -> Vec<String> {
+    pub fn to_mpd_format(&self) -> Vec<String> {
         let mut output = vec![];
 
         output.push(format!("file: {}", self.id.as_ref().unwrap()));
@@ -31,11 +33,84 @@ impl Track {
         output.push(format!("Date: {}", self.date));
         output.push(format!("Time: {}", self.duration / 1000));
         output.push(format!("duration: {}", self.duration / 1000));
+
+        output
+    }
+
+    /// The song information followed by where the track sits in the queue
+    pub fn to_mpd_queue_format(&self, pos: usize, id: usize) -> Vec<String> {
+        let mut output = self.to_mpd_format();
+
         output.push(format!("Pos: {}", pos));
-        output.push(format!("Id: {}", pos));
+        output.push(format!("Id: {}", id));
 
         output
     }
+
+    /// Builds the track from an album listing, which leaves the album out of each track
+    pub fn from_album_track(track: &SimplifiedTrack, album: &Album) -> Self {
+        Self {
+            id: track.id.clone(),
+            title: track.name.clone(),
+            track_number: track.track_number,
+            disc_number: track.disc_number,
+            duration: track.duration_ms,
+            artists: track.artists.iter().map(|artist| artist.name.clone()).collect(),
+            album: album.name.clone(),
+            album_id: Some(album.id.clone()),
+            album_artists: album.artists.clone(),
+            url: track.uri.clone(),
+            added_at: None,
+            date: album.release_date.clone(),
+        }
+    }
+
+    /// Whether this is a Spotify track rather than a podcast episode, which Spotify
+    /// playlists only take through the Web API
+    pub fn is_track(&self) -> bool {
+        self.url.starts_with("spotify:track:")
+    }
+
+    /// Local tracks have no id we could play them by
+    pub fn is_playable(&self) -> bool {
+        self.id.is_some()
+    }
+
+    /// An episode looked up on its own, which names its show
+    pub fn from_full_episode(episode: &FullEpisode) -> Self {
+        Self {
+            id: Some(episode.id.clone()),
+            title: episode.name.clone(),
+            track_number: 0,
+            disc_number: 0,
+            duration: episode.duration_ms,
+            artists: vec![episode.show.publisher.clone()],
+            album: episode.show.name.clone(),
+            album_id: None,
+            album_artists: vec![episode.show.publisher.clone()],
+            url: episode.uri.clone(),
+            added_at: None,
+            date: episode.release_date.clone(),
+        }
+    }
+
+    /// Podcast episodes are shown as songs of an album named after the show
+    pub fn from_episode(episode: &SimplifiedEpisode, show: &Show) -> Self {
+        Self {
+            id: Some(episode.id.clone()),
+            title: episode.name.clone(),
+            track_number: 0,
+            disc_number: 0,
+            duration: episode.duration_ms,
+            artists: vec![show.publisher.clone()],
+            album: show.name.clone(),
+            album_id: None,
+            album_artists: vec![show.publisher.clone()],
+            url: episode.uri.clone(),
+            added_at: None,
+            date: episode.release_date.clone(),
+        }
+    }
 }
 
 impl From<&FullTrack> for Track {
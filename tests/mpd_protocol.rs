@@ -0,0 +1,789 @@
+use futures::channel::mpsc;
+use spotify_mpd::catalog::{fixture_episode, fixture_track, Album, Artist, FixtureCatalog, Show};
+use spotify_mpd::mpd::MpdServer;
+use spotify_mpd::queue::Queue;
+use spotify_mpd::respot::player_worker::PlayerWorker;
+use spotify_mpd::respot::{SimulatedEngine, VirtualClock};
+use spotify_mpd::state::StateFile;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An MPD server on an ephemeral port, backed by an in-memory catalog and a player that
+/// only advances when the test moves its clock
+struct TestServer {
+    address: SocketAddr,
+    clock: VirtualClock,
+    queue: Arc<Queue>,
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        let catalog = FixtureCatalog::new("tester");
+        let album = Album {
+            id: "album1".to_owned(),
+            name: "Test Album".to_owned(),
+            artists: vec!["Test Artist".to_owned()],
+            release_date: "2021-03-04".to_owned(),
+        };
+        let tracks = vec![
+            fixture_track("track1", "First", &album, 1, 180_000),
+            fixture_track("track2", "Second", &album, 2, 240_000),
+        ];
+        catalog.add_album(album, tracks);
+        catalog.save_album("album1");
+        catalog.add_artist(Artist { id: "artist1".to_owned(), name: "Test Artist".to_owned() }, vec!["album1".to_owned()]);
+        catalog.follow_artist("artist1");
+
+        let show = Show {
+            id: "show1".to_owned(),
+            name: "Test Show".to_owned(),
+            publisher: "Test Publisher".to_owned(),
+        };
+        let episode = fixture_episode("episode1", "Pilot", &show, "2021-05-06", 1_800_000);
+        catalog.add_show(show, vec![episode]);
+
+        let (command_sender, command_receiver) = mpsc::unbounded();
+        let (event_sender, event_receiver) = std::sync::mpsc::channel();
+        let (event_bus, _) = broadcast::channel(100);
+
+        let queue = Arc::new(Queue::new(Arc::new(Mutex::new(command_sender)), event_bus.clone()));
+        Queue::start_worker(queue.clone(), event_receiver);
+
+        let clock = VirtualClock::new();
+        let engine_clock = clock.clone();
+        thread::spawn(move || {
+            let engine = Box::new(SimulatedEngine::new(engine_clock));
+            let _ = futures::executor::block_on(PlayerWorker::new(engine, command_receiver, event_sender));
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = MpdServer::new(address.to_string(), Arc::new(catalog), queue.clone(), event_bus);
+        tokio::spawn(async move { server.serve(listener).await });
+
+        Self {
+            address,
+            clock,
+            queue,
+        }
+    }
+
+    async fn connect(&self) -> TestClient {
+        let stream = TcpStream::connect(self.address).await.unwrap();
+        let mut client = TestClient {
+            stream: BufReader::new(stream),
+        };
+        assert_eq!(client.read_line().await, "OK MPD 0.21.11");
+
+        client
+    }
+}
+
+struct TestClient {
+    stream: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    async fn send(&mut self, line: &str) {
+        self.stream.get_mut().write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+    }
+
+    async fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        tokio::time::timeout(TIMEOUT, self.stream.read_line(&mut line))
+            .await
+            .expect("Timed out waiting for the server")
+            .unwrap();
+
+        line.trim_end_matches('\n').to_owned()
+    }
+
+    /// Reads lines up to and including the `OK` or `ACK` that ends a response
+    async fn read_response(&mut self) -> Vec<String> {
+        let mut response = vec![];
+        loop {
+            let line = self.read_line().await;
+            let done = line == "OK" || line.starts_with("ACK ");
+            response.push(line);
+            if done {
+                return response;
+            }
+        }
+    }
+
+    async fn command(&mut self, line: &str) -> Vec<String> {
+        self.send(line).await;
+        self.read_response().await
+    }
+
+    /// The status without the lines that depend on the wall clock
+    async fn status(&mut self) -> Vec<String> {
+        self.command("status").await
+            .into_iter()
+            .filter(|line| !line.starts_with("time: ") && !line.starts_with("elapsed: "))
+            .collect()
+    }
+
+    /// Polls the status until it matches, the player reports its changes asynchronously
+    async fn wait_for_status(&mut self, expected: Vec<&str>) {
+        for _ in 0..500 {
+            if self.status().await == expected {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(self.status().await, expected);
+    }
+
+    /// The value of one status line like `songid`, if the status has it
+    async fn status_field(&mut self, field: &str) -> Option<String> {
+        let prefix = format!("{}: ", field);
+        self.command("status").await
+            .into_iter()
+            .find_map(|line| line.strip_prefix(&prefix).map(str::to_owned))
+    }
+
+    /// Polls the status until the line has the value, or is gone for `None`
+    async fn wait_for_field(&mut self, field: &str, expected: Option<&str>) {
+        for _ in 0..500 {
+            if self.status_field(field).await.as_deref() == expected {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(self.status_field(field).await.as_deref(), expected);
+    }
+
+    /// The song ids of the queue, in queue order
+    async fn song_ids(&mut self) -> Vec<String> {
+        self.command("playlistid").await
+            .into_iter()
+            .filter_map(|line| line.strip_prefix("Id: ").map(str::to_owned))
+            .collect()
+    }
+}
+
+fn stopped_status(playlist_version: u32, playlist_length: usize) -> Vec<String> {
+    vec![
+        "mixrampdb: 0.00000".to_owned(),
+        "repeat: 0".to_owned(),
+        "random: 0".to_owned(),
+        "single: 0".to_owned(),
+        "consume: 0".to_owned(),
+        format!("playlist: {}", playlist_version),
+        "volume: 100".to_owned(),
+        format!("playlistlength: {}", playlist_length),
+        "state: stop".to_owned(),
+        "OK".to_owned(),
+    ]
+}
+
+#[tokio::test]
+async fn status_of_an_empty_queue() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("status").await, stopped_status(1, 0));
+    assert_eq!(client.command("ping").await, vec!["OK"]);
+}
+
+#[tokio::test]
+async fn add_and_playlistinfo() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add \"track2\"").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("playlistinfo").await, vec![
+        "file: track1",
+        "Artist: Test Artist",
+        "AlbumArtist: Test Artist",
+        "Title: First",
+        "Album: Test Album",
+        "Track: 1",
+        "Date: 2021-03-04",
+        "Time: 180",
+        "duration: 180",
+        "Pos: 0",
+        "Id: 1",
+        "file: track2",
+        "Artist: Test Artist",
+        "AlbumArtist: Test Artist",
+        "Title: Second",
+        "Album: Test Album",
+        "Track: 2",
+        "Date: 2021-03-04",
+        "Time: 240",
+        "duration: 240",
+        "Pos: 1",
+        "Id: 2",
+        "OK",
+    ]);
+    assert_eq!(client.command("status").await, stopped_status(3, 2));
+}
+
+#[tokio::test]
+async fn episodes_are_added_like_songs() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("addid episode1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("playlistid 1").await[..4], [
+        "file: episode1",
+        "Artist: Test Publisher",
+        "AlbumArtist: Test Publisher",
+        "Title: Pilot",
+    ]);
+}
+
+#[tokio::test]
+async fn playlists_leave_episodes_out() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add episode1").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 3", "OK"]);
+    assert_eq!(client.command("save mix").await, vec!["OK"]);
+    assert_eq!(client.command("listplaylist mix").await, vec!["file: track1", "file: track2", "OK"]);
+
+    assert_eq!(
+        client.command("playlistadd mix episode1").await,
+        vec!["ACK [2@0] {playlistadd} Podcast episodes can't be added to playlists"],
+    );
+    assert_eq!(client.command("playlistadd mix track1").await, vec!["OK"]);
+    assert_eq!(client.command("listplaylist mix").await, vec!["file: track1", "file: track2", "file: track1", "OK"]);
+}
+
+#[tokio::test]
+async fn library_directories_are_walked_and_added() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track2").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("save mix").await, vec!["OK"]);
+    assert_eq!(client.command("listall").await, vec![
+        "directory: Playlists",
+        "directory: Playlists/mix",
+        "file: track2",
+        "directory: Liked Songs",
+        "directory: Albums",
+        "directory: Albums/Test Album",
+        "file: track1",
+        "file: track2",
+        "directory: Artists",
+        "directory: Artists/Test Artist",
+        "directory: Artists/Test Artist/Test Album",
+        "file: track1",
+        "file: track2",
+        "directory: Podcasts",
+        "directory: Podcasts/Test Show",
+        "file: episode1",
+        "OK",
+    ]);
+
+    assert_eq!(client.command("add \"Artists/Test Artist\"").await, vec!["Id: 2", "Id: 3", "OK"]);
+    assert_eq!(client.command("addid Playlists/mix 0").await, vec!["Id: 4", "OK"]);
+    assert_eq!(client.song_ids().await, vec!["4", "1", "2", "3"]);
+    assert_eq!(
+        client.command("add \"Albums/No Album\"").await,
+        vec!["ACK [50@0] {add} No such directory"],
+    );
+}
+
+#[tokio::test]
+async fn errors_are_acknowledged_with_their_code() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add nope").await, vec!["ACK [50@0] {add} No such song"]);
+    assert_eq!(client.command("add").await, vec!["ACK [2@0] {add} wrong number of arguments"]);
+    assert_eq!(client.command("setvol 101").await, vec!["ACK [2@0] {setvol} Invalid volume value"]);
+    assert_eq!(client.command("play first").await, vec!["ACK [2@0] {play} Integer expected: first"]);
+    assert_eq!(client.command("play 99").await, vec!["ACK [2@0] {play} Bad song index"]);
+    assert_eq!(client.command("frobnicate").await, vec!["ACK [5@0] {frobnicate} unknown command"]);
+    assert_eq!(client.command("seek 99 30").await, vec!["ACK [2@0] {seek} Bad song index"]);
+    assert_eq!(client.command("seekcur 10").await, vec!["ACK [55@0] {seekcur} Not playing"]);
+
+    // The connection stays usable after an error
+    assert_eq!(client.command("status").await, stopped_status(1, 0));
+}
+
+#[tokio::test]
+async fn seek_starts_a_stopped_song() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    // Positions too far off for a duration are cut short rather than taking the connection down
+    assert_eq!(client.command("seekcur 1e300").await, vec!["ACK [55@0] {seekcur} Not playing"]);
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("play").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+    assert_eq!(client.command("stop").await, vec!["OK"]);
+    client.wait_for_field("state", Some("stop")).await;
+
+    // The stopped song is kept, but there is nothing to seek in
+    assert_eq!(client.command("seekcur 10").await, vec!["ACK [55@0] {seekcur} Not playing"]);
+
+    // Seeking the stopped song starts it at the position
+    assert_eq!(client.command("seek 0 170").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+    server.clock.advance(Duration::from_secs(15));
+    client.wait_for_field("songid", Some("2")).await;
+
+    assert_eq!(client.command("seekid 2 1e300").await, vec!["OK"]);
+    client.wait_for_field("state", Some("stop")).await;
+}
+
+#[tokio::test]
+async fn command_lists() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    client.send("command_list_begin").await;
+    client.send("add track1").await;
+    client.send("add track2").await;
+    assert_eq!(client.command("command_list_end").await, vec!["Id: 1", "Id: 2", "OK"]);
+
+    client.send("command_list_ok_begin").await;
+    client.send("ping").await;
+    client.send("add track1").await;
+    assert_eq!(client.command("command_list_end").await, vec!["list_OK", "Id: 3", "list_OK", "OK"]);
+
+    // The list stops at the first failing command, which the ACK points at
+    client.send("command_list_begin").await;
+    client.send("add track2").await;
+    client.send("add nope").await;
+    client.send("add track1").await;
+    assert_eq!(client.command("command_list_end").await, vec![
+        "Id: 4",
+        "ACK [50@1] {add} No such song",
+    ]);
+    assert_eq!(client.command("status").await, stopped_status(5, 4));
+
+    // Commands acting on the connection itself can't be part of a list
+    client.send("command_list_begin").await;
+    client.send("ping").await;
+    client.send("idle").await;
+    assert_eq!(client.command("command_list_end").await, vec!["ACK [2@1] {idle} idle not allowed in command list"]);
+    assert_eq!(client.command("ping").await, vec!["OK"]);
+}
+
+#[tokio::test]
+async fn idle_notifications() {
+    let server = TestServer::start().await;
+    let mut idler = server.connect().await;
+    let mut client = server.connect().await;
+
+    idler.send("idle playlist").await;
+    assert_eq!(client.command("setvol 50").await, vec!["OK"]);
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(idler.read_response().await, vec!["changed: playlist", "OK"]);
+
+    // The mixer change was held back until the idler asks for it
+    assert_eq!(idler.command("idle").await, vec!["changed: mixer", "OK"]);
+
+    idler.send("idle").await;
+    assert_eq!(idler.command("noidle").await, vec!["OK"]);
+
+    assert_eq!(idler.command("idle bogus").await, vec!["ACK [2@0] {idle} Unrecognized idle event: bogus"]);
+}
+
+#[tokio::test]
+async fn playback_follows_the_queue() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    let mut idler = server.connect().await;
+    idler.send("idle player").await;
+
+    assert_eq!(client.command("play").await, vec!["OK"]);
+    assert_eq!(idler.read_response().await, vec!["changed: player", "OK"]);
+    client.wait_for_status(vec![
+        "mixrampdb: 0.00000",
+        "repeat: 0",
+        "random: 0",
+        "single: 0",
+        "consume: 0",
+        "playlist: 3",
+        "volume: 100",
+        "playlistlength: 2",
+        "state: play",
+        "song: 0",
+        "songid: 1",
+        "nextsong: 1",
+        "nextsongid: 2",
+        "duration: 180",
+        "audio: 44100:24:2",
+        "bitrate: 320",
+        "OK",
+    ]).await;
+
+    server.clock.advance(Duration::from_secs(180));
+    client.wait_for_status(vec![
+        "mixrampdb: 0.00000",
+        "repeat: 0",
+        "random: 0",
+        "single: 0",
+        "consume: 0",
+        "playlist: 3",
+        "volume: 100",
+        "playlistlength: 2",
+        "state: play",
+        "song: 1",
+        "songid: 2",
+        "duration: 240",
+        "audio: 44100:24:2",
+        "bitrate: 320",
+        "OK",
+    ]).await;
+
+    server.clock.advance(Duration::from_secs(240));
+    let stopped = stopped_status(3, 2);
+    client.wait_for_status(stopped.iter().map(String::as_str).collect()).await;
+}
+
+#[tokio::test]
+async fn preloaded_track_follows_without_a_gap() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    let mut idler = server.connect().await;
+    idler.send("idle player").await;
+
+    // Once clients hear that playback started, the second track is preloaded
+    assert_eq!(client.command("play").await, vec!["OK"]);
+    assert_eq!(idler.read_response().await, vec!["changed: player", "OK"]);
+
+    // The clock jumps past the end of the first track, the second one started right at it
+    idler.send("idle player").await;
+    server.clock.advance(Duration::from_secs(185));
+    assert_eq!(idler.read_response().await, vec!["changed: player", "OK"]);
+    client.wait_for_status(vec![
+        "mixrampdb: 0.00000",
+        "repeat: 0",
+        "random: 0",
+        "single: 0",
+        "consume: 0",
+        "playlist: 3",
+        "volume: 100",
+        "playlistlength: 2",
+        "state: play",
+        "song: 1",
+        "songid: 2",
+        "duration: 240",
+        "audio: 44100:24:2",
+        "bitrate: 320",
+        "OK",
+    ]).await;
+
+    // So it ends 240 seconds after the first one did, not after the clock jumped
+    server.clock.advance(Duration::from_secs(235));
+    let stopped = stopped_status(3, 2);
+    client.wait_for_status(stopped.iter().map(String::as_str).collect()).await;
+}
+
+#[tokio::test]
+async fn state_survives_a_restart() {
+    let path = std::env::temp_dir().join(format!("spotify-mpd-state-{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("setvol 40").await, vec!["OK"]);
+    assert_eq!(client.command("repeat 1").await, vec!["OK"]);
+    assert_eq!(client.command("play 1").await, vec!["OK"]);
+    client.wait_for_status(vec![
+        "mixrampdb: 0.00000",
+        "repeat: 1",
+        "random: 0",
+        "single: 0",
+        "consume: 0",
+        "playlist: 3",
+        "volume: 40",
+        "playlistlength: 2",
+        "state: play",
+        "song: 1",
+        "songid: 2",
+        "nextsong: 0",
+        "nextsongid: 1",
+        "duration: 240",
+        "audio: 44100:24:2",
+        "bitrate: 320",
+        "OK",
+    ]).await;
+    assert_eq!(client.command("seekcur 100").await, vec!["OK"]);
+    StateFile::new(path, server.queue.clone()).save();
+
+    // Without resuming, the track that was playing comes back paused
+    let restarted = TestServer::start().await;
+    StateFile::new(path, restarted.queue.clone()).restore(false);
+    let mut client = restarted.connect().await;
+    client.wait_for_status(vec![
+        "mixrampdb: 0.00000",
+        "repeat: 1",
+        "random: 0",
+        "single: 0",
+        "consume: 0",
+        "playlist: 2",
+        "volume: 40",
+        "playlistlength: 2",
+        "state: pause",
+        "song: 1",
+        "songid: 2",
+        "nextsong: 0",
+        "nextsongid: 1",
+        "duration: 240",
+        "audio: 44100:24:2",
+        "bitrate: 320",
+        "OK",
+    ]).await;
+    assert_eq!(client.command("add track1").await, vec!["Id: 3", "OK"]);
+
+    // Resuming plays it on from where it was
+    let resumed = TestServer::start().await;
+    StateFile::new(path, resumed.queue.clone()).restore(true);
+    let mut client = resumed.connect().await;
+    client.wait_for_field("state", Some("play")).await;
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("2"));
+    let elapsed: f32 = client.status_field("elapsed").await.unwrap().parse().unwrap();
+    assert!((100.0..110.0).contains(&elapsed), "elapsed {}", elapsed);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn repeat_and_single_decide_what_follows() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("repeat 1").await, vec!["OK"]);
+    assert_eq!(client.command("play 1").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+
+    // Repeat goes back to the start of the queue after the last song
+    server.clock.advance(Duration::from_secs(240));
+    client.wait_for_field("songid", Some("1")).await;
+
+    // Single with repeat plays the current song again instead of the next one
+    assert_eq!(client.command("single 1").await, vec!["OK"]);
+    let mut idler = server.connect().await;
+    idler.send("idle player").await;
+    server.clock.advance(Duration::from_secs(180));
+    assert_eq!(idler.read_response().await, vec!["changed: player", "OK"]);
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("1"));
+    assert_eq!(client.status_field("state").await.as_deref(), Some("play"));
+
+    // Oneshot stops after the current song and then turns itself off
+    assert_eq!(client.command("single oneshot").await, vec!["OK"]);
+    server.clock.advance(Duration::from_secs(180));
+    client.wait_for_field("state", Some("stop")).await;
+    assert_eq!(client.status_field("single").await.as_deref(), Some("0"));
+    assert_eq!(client.status_field("songid").await, None);
+}
+
+#[tokio::test]
+async fn previous_restarts_the_first_song() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("play 0").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+    assert_eq!(client.command("seekcur 170").await, vec!["OK"]);
+
+    // Without repeat there is nothing before the first song, so it starts over
+    assert_eq!(client.command("previous").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("1"));
+    server.clock.advance(Duration::from_secs(15));
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("1"));
+}
+
+#[tokio::test]
+async fn random_plays_every_song_once() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    for id in 1..=3 {
+        assert_eq!(client.command("add track1").await, vec![format!("Id: {}", id), "OK".to_owned()]);
+    }
+    assert_eq!(client.command("random 1").await, vec!["OK"]);
+    assert_eq!(client.command("play 0").await, vec!["OK"]);
+
+    let mut played = vec![client.status_field("songid").await.unwrap()];
+    for _ in 0..2 {
+        assert_eq!(client.command("next").await, vec!["OK"]);
+        played.push(client.status_field("songid").await.unwrap());
+    }
+    played.sort();
+    assert_eq!(played, vec!["1", "2", "3"]);
+
+    // Without repeat, playback ends once every song had its turn
+    assert_eq!(client.command("next").await, vec!["OK"]);
+    client.wait_for_field("state", Some("stop")).await;
+    assert_eq!(client.status_field("songid").await, None);
+}
+
+#[tokio::test]
+async fn consume_removes_played_songs() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("add track1").await, vec!["Id: 3", "OK"]);
+    assert_eq!(client.command("consume 1").await, vec!["OK"]);
+    assert_eq!(client.command("play 0").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+
+    // A song that played to its end is removed
+    server.clock.advance(Duration::from_secs(180));
+    client.wait_for_field("songid", Some("2")).await;
+    assert_eq!(client.song_ids().await, vec!["2", "3"]);
+    assert_eq!(client.status_field("song").await.as_deref(), Some("0"));
+
+    // So is one that was skipped
+    assert_eq!(client.command("next").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["3"]);
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("3"));
+
+    // Skipping past the last one leaves an empty queue behind
+    assert_eq!(client.command("next").await, vec!["OK"]);
+    client.wait_for_field("state", Some("stop")).await;
+    assert_eq!(client.status_field("playlistlength").await.as_deref(), Some("0"));
+}
+
+#[tokio::test]
+async fn plchanges_lists_what_changed_since_a_version() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.status_field("playlist").await.as_deref(), Some("3"));
+    assert_eq!(client.command("plchanges 2").await, vec![
+        "file: track2",
+        "Artist: Test Artist",
+        "AlbumArtist: Test Artist",
+        "Title: Second",
+        "Album: Test Album",
+        "Track: 2",
+        "Date: 2021-03-04",
+        "Time: 240",
+        "duration: 240",
+        "Pos: 1",
+        "Id: 2",
+        "OK",
+    ]);
+    assert_eq!(client.command("plchangesposid 3").await, vec!["OK"]);
+    assert_eq!(client.command("plchangesposid 0").await, vec!["cpos: 0", "Id: 1", "cpos: 1", "Id: 2", "OK"]);
+
+    // Only the songs that changed position count as changed
+    assert_eq!(client.command("add track1").await, vec!["Id: 3", "OK"]);
+    assert_eq!(client.command("swap 0 1").await, vec!["OK"]);
+    assert_eq!(client.command("plchangesposid 4").await, vec!["cpos: 0", "Id: 2", "cpos: 1", "Id: 1", "OK"]);
+
+    // Removing a song moves up everything after it
+    assert_eq!(client.command("delete 0").await, vec!["OK"]);
+    assert_eq!(client.command("plchangesposid 5").await, vec!["cpos: 0", "Id: 1", "cpos: 1", "Id: 3", "OK"]);
+}
+
+#[tokio::test]
+async fn move_swap_and_shuffle_keep_the_current_song() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    for id in 1..=4 {
+        assert_eq!(client.command("add track1").await, vec![format!("Id: {}", id), "OK".to_owned()]);
+    }
+    assert_eq!(client.command("play 1").await, vec!["OK"]);
+
+    assert_eq!(client.command("move 1 3").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["1", "3", "4", "2"]);
+    assert_eq!(client.status_field("song").await.as_deref(), Some("3"));
+
+    assert_eq!(client.command("move 0:2 2").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["4", "2", "1", "3"]);
+    assert_eq!(client.status_field("song").await.as_deref(), Some("1"));
+
+    assert_eq!(client.command("swap 0 1").await, vec!["OK"]);
+    assert_eq!(client.command("swapid 1 3").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["2", "4", "3", "1"]);
+    assert_eq!(client.status_field("song").await.as_deref(), Some("0"));
+
+    // Positions past the end leave the queue as it is
+    assert_eq!(client.command("move 3 4").await, vec!["ACK [2@0] {move} Bad song index"]);
+    assert_eq!(client.command("swap 0 9").await, vec!["ACK [2@0] {swap} Bad song index"]);
+    assert_eq!(client.command("shuffle 2:9").await, vec!["ACK [2@0] {shuffle} Bad song index"]);
+    assert_eq!(client.song_ids().await, vec!["2", "4", "3", "1"]);
+
+    assert_eq!(client.command("shuffle").await, vec!["OK"]);
+    let mut song_ids = client.song_ids().await;
+    let position = song_ids.iter().position(|id| id == "2").unwrap();
+    assert_eq!(client.status_field("song").await, Some(position.to_string()));
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("2"));
+    song_ids.sort();
+    assert_eq!(song_ids, vec!["1", "2", "3", "4"]);
+}
+
+#[tokio::test]
+async fn insert_and_delete_keep_the_current_song() {
+    let server = TestServer::start().await;
+    let mut client = server.connect().await;
+
+    assert_eq!(client.command("add track1").await, vec!["Id: 1", "OK"]);
+    assert_eq!(client.command("add track2").await, vec!["Id: 2", "OK"]);
+    assert_eq!(client.command("add track1").await, vec!["Id: 3", "OK"]);
+    assert_eq!(client.command("play 1").await, vec!["OK"]);
+    client.wait_for_field("state", Some("play")).await;
+
+    // Inserting before the current song moves it down
+    assert_eq!(client.command("addid track2 0").await, vec!["Id: 4", "OK"]);
+    assert_eq!(client.command("addid track1 +0").await, vec!["Id: 5", "OK"]);
+    assert_eq!(client.command("addid track1 -0").await, vec!["Id: 6", "OK"]);
+    assert_eq!(client.song_ids().await, vec!["4", "1", "6", "2", "5", "3"]);
+    assert_eq!(client.status_field("song").await.as_deref(), Some("3"));
+    assert_eq!(client.command("addid track1 9").await, vec!["ACK [2@0] {addid} Bad song index"]);
+    assert_eq!(client.command("addid track1 +18446744073709551615").await, vec!["ACK [2@0] {addid} Bad song index"]);
+
+    // Removing songs before it moves it up
+    assert_eq!(client.command("delete 0:2").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["6", "2", "5", "3"]);
+    assert_eq!(client.status_field("song").await.as_deref(), Some("1"));
+    assert_eq!(client.command("delete 2:9").await, vec!["ACK [2@0] {delete} Bad song index"]);
+    assert_eq!(client.command("delete 18446744073709551615").await, vec!["ACK [2@0] {delete} Bad song index"]);
+    assert_eq!(client.command("moveid 2 18446744073709551615").await, vec!["ACK [2@0] {moveid} Bad song index"]);
+
+    // Removing the song that plays goes on with the one after it
+    assert_eq!(client.command("delete 1").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["6", "5", "3"]);
+    assert_eq!(client.status_field("songid").await.as_deref(), Some("5"));
+    client.wait_for_field("state", Some("play")).await;
+
+    // Unless it was the last one
+    assert_eq!(client.command("delete 1:").await, vec!["OK"]);
+    assert_eq!(client.song_ids().await, vec!["6"]);
+    client.wait_for_field("state", Some("stop")).await;
+}